@@ -0,0 +1,22 @@
+//! 解析器的模糊测试, 任意输入都不能导致 panic.
+//!
+//! 运行: `cargo +nightly fuzz run parse_frame`
+#![no_main]
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use std::io::Cursor;
+use tokio_cn_doc::relational::codec::{Decoder, RespCodec};
+use tokio_cn_doc::relational::frame_enum::Frame;
+
+fuzz_target!(|data: &[u8]| {
+    // 先 check 再 parse, 与 `RespCodec` 的用法相同
+    let mut src = Cursor::new(data);
+    if Frame::check(&mut src).is_ok() {
+        let _ = Frame::parse(&mut Cursor::new(data));
+    }
+
+    // 通过编解码器连续解码, 覆盖 inline 命令与缓冲区的切分
+    let mut codec = RespCodec::new();
+    let mut buf = BytesMut::from(data);
+    while let Ok(Some(_)) = codec.decode(&mut buf) {}
+});
@@ -1 +0,0 @@
-pub mod delay;
\ No newline at end of file
@@ -1,237 +1,231 @@
-//! 演示了如何实现一个非常基础的异步Rust执行器与计时器.
-use std::pin::Pin;
+//! 演示了如何实现一个非常基础的异步Rust执行器与计时器. 执行器的实现在 `tokio_cn_doc::mini_tokio` 中.
 use std::time::{Instant, Duration};
-use std::future::Future;
-use std::task::{Context, Poll, Waker};
-use std::option::Option::Some;
-use std::result::Result::Ok;
-use std::sync::{Arc, Mutex};
-use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::thread;
-// 使用一个channel队列来调度tasks, 之所以不用std中的channel是因为std中的channel不是 Sync的，无法在线程中共享
-use crossbeam::channel;
-// 允许我们不使用 “不安全” 的代码来实现一个 `sta::task::waker` 功能的 工具
-use futures::task::{ArcWake, self};
-
-// 用于跟踪当前的mini-tokio实例,来使 spawn 函数能调度产生的实例.
-thread_local! {
-    static CURRENT: RefCell<Option<channel::Sender<Arc<Task>>>> = RefCell::new(None);
-}
+
+use futures::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_cn_doc::mini_tokio::net::TcpListener;
+use tokio_cn_doc::mini_tokio::time::delay;
+use tokio_cn_doc::mini_tokio::{panic_message, spawn, spawn_blocking, spawn_local, yield_now, LocalSet, MiniTokio, Scheduler};
+use tokio_cn_doc::task_local;
 
 /// main 函数入口，创建一个mini-tokio实例，并产生一些任务(Task). 我们的mini-tokio实现仅支持产生task和设置delays
 ///
 /// 此MiniTokio，源于官方指南中的MiniTokio 原理代码，原代码链接 [mini-tokio](https://github.com/tokio-rs/website/blob/master/tutorial-code/mini-tokio/src/main.rs)
 fn main() {
-    // 创建一个新MiniTokio实例
-    let mut mini_tokio = MiniTokio::new();
+    match std::env::args().nth(1).as_deref() {
+        // `cargo run --release --bin mini-tokio -- bench` 对比两种调度器的性能
+        Some("bench") => return bench(),
+        // `cargo run --bin mini-tokio -- echo` 在 mini-tokio 上运行 echo-server
+        Some("echo") => return echo(),
+        _ => {}
+    }
+
+    // 创建一个使用4个线程执行任务的MiniTokio实例, 任务 panic 时打印一条消息
+    let mut mini_tokio = MiniTokio::builder()
+        .worker_threads(4)
+        .on_task_panic(|payload| println!("task panicked: {}", panic_message(payload).unwrap_or("Box<dyn Any>")))
+        .build()
+        .unwrap();
+    let shutdown = mini_tokio.shutdown_handle();
+
+    // block_on 在当前线程上运行一个 future 直到完成, 并返回它的输出. 当前线程同时作为一个 worker 执行产生的任务
+    let sum = mini_tokio.block_on(async {
+        let handles: Vec<_> = (1..=10)
+            .map(|i| spawn(async move {
+                delay(Duration::from_millis(10 * i)).await;
+                i
+            }))
+            .collect();
+
+        let mut sum = 0;
+        for handle in handles {
+            sum += handle.await.unwrap();
+        }
+        sum
+    });
+    println!("block_on returned {}", sum);
+
+    // LocalSet 中的任务只在当前线程上执行, 所以可以跨 .await 持有 Rc 这样不是 Send 的值, 见 send-bound 示例
+    let local = LocalSet::new();
+    local.block_on(&mini_tokio, async {
+        let rc = Rc::new("hello");
+        spawn_local(async move {
+            yield_now().await;
+            println!("{} from spawn_local", rc);
+        }).await.unwrap();
+    });
 
     // 产生一个root 根据任务，所有其它的tasks都来自于这个上下文.  直到mini_tokio.run()调用之前，不会执行任何工作.
-    mini_tokio.spawn(async {
+    mini_tokio.spawn(async move {
         // 产生一个任务
-        spawn(async {
+        let world = spawn(async {
             // 等待一小段时间以便 world在 hello后面打印
             delay(Duration::from_secs(5)).await;
-            println!("world")
+            println!("world");
+            "world"
         });
 
         // 产生第二个任务
-        spawn(async {
+        let hello = spawn(async {
            println!("hello");
+           "hello"
         });
 
-        //我们没有实现执行器的shutdown功能，因此这里强制关闭
-        delay(Duration::from_secs(10)).await; // 2秒后关闭吧
-        std::process::exit(0);
-    });
-
-
-    // 启动mini-tokio 执行器循环，调度任务并接收执行结果
-    mini_tokio.run();
-}
-
-/// 此spawn函数功能与tokio::spawn()一样. 当进行到mini-tokio执行器(executor)中时,
-/// 'CURRENT' 本地线程(thread-local) 被设置指向执行器 channel的 Send 方. 然后，产生task需要为创建的"Task"套上
-/// 一个"future" 并将其推到调度队列里面.
-pub fn spawn<F>(future: F)
-where F: Future<Output =()> + Send + 'static,
-{
-    CURRENT.with(|cell| {
-        let borrow = cell.borrow();
-        let sender = borrow.as_ref().unwrap();
-        Task::spawn(future, sender);
-    });
-}
+        // 通过 JoinHandle 等待子任务完成并取得它的输出
+        println!("joined: {} {}", hello.await.unwrap(), world.await.unwrap());
 
-/// task 包含一个future和一旦future被唤醒后所必须要的数据
-struct Task {
-    // future使用 Mutex 来包装可以使用Task具有Sync 特性.
-    // 仅有一个线程可以使用future.
-    // 真实tokio运行时，没有使用Mutex这种排它锁，而是使用了unsafe代码. box也被避免使用了.
-    future: Mutex<Pin<Box<dyn Future<Output = ()> + Send>>>,
-    // 当task被通知时，它被发送到队列中去. 执行器通过取出通知任务来执行它们
-    executor: channel::Sender<Arc<Task>>,
-}
+        // 子任务中的 panic 不会影响当前任务, 而是作为 JoinError 返回
+        let err = spawn(async { panic!("boom") }).await.unwrap_err();
+        println!("child panicked: {}", err.is_panic());
 
-impl Task {
-    // 使用指定的future产生一个新的future
-    // 初始化一个新的包含了指定future的task，并将其它推送给 sender. channel另外一半的receiver将接收到它并执行.
-    fn spawn<F>(future: F, sender: &channel::Sender<Arc<Task>>)
-        where F: Future<Output = ()> + Send + 'static,
-    {
-        let task = Arc::new(Task {
-            future: Mutex::new(Box::pin(future)),
-            executor: sender.clone(),
+        // 取消一个子任务, 它的 future 会被 drop
+        let guard = DropGuard("aborted task");
+        let aborted = spawn(async move {
+            let _guard = guard;
+            delay(Duration::from_secs(3600)).await;
         });
-        let _ = sender.send(task);
-    }
-
-    // 执行调度任务. 它创建了必须的 `task::Context`上下文，此Context,包含了一个waker与task.
-    // 使用waker对future进行poll.
-    fn poll(self: Arc<Self>) {
-        // 从task实例上创建一个waker, 它使用了 ArcWake
-        let waker = task::waker(self.clone());
-        // 使用waker来初始化task的上下文
-        let mut cx = Context::from_waker(&waker);
-
-        // 这里绝不会阻塞，因为只有一个线程能锁住future
-        let mut future = self.future.try_lock().unwrap();
+        aborted.abort();
+        println!("child cancelled: {}", aborted.await.unwrap_err().is_cancelled());
+
+        // 4个任务中的阻塞操作在不同的 worker 上并行执行, 总共只需要大约1秒
+        let start = Instant::now();
+        let blocking: Vec<_> = (0..4)
+            .map(|_| spawn(async {
+                thread::sleep(Duration::from_secs(1));
+                thread::current().name().unwrap_or("main").to_string()
+            }))
+            .collect();
+        for handle in blocking {
+            println!("slept on {}", handle.await.unwrap());
+        }
+        println!("4 blocking tasks took {:?}", start.elapsed());
+
+        // 阻塞操作更应该交给 spawn_blocking, 它们在专门的线程池中执行, 不占用 worker, 8个任务也只需要大约1秒
+        let start = Instant::now();
+        let blocking: Vec<_> = (0..8)
+            .map(|_| spawn_blocking(|| thread::sleep(Duration::from_secs(1))))
+            .collect();
+        for handle in blocking {
+            handle.await.unwrap();
+        }
+        println!("8 spawn_blocking tasks took {:?}", start.elapsed());
 
-        // 轮询future
-        let _ = future.as_mut().poll(&mut cx);
-    }
-}
+        // 产生一个很久才会完成的任务, 关闭执行器时它的 future 会被 drop
+        let guard = DropGuard("long task");
+        spawn(async move {
+            let _guard = guard;
+            delay(Duration::from_secs(3600)).await;
+        });
 
-// 在标准库中使用了一个低级别的API来定义waker,此API是unsafe的，为了不写unsafe代码，这里我们使用futures包提供的
-// ArcWake 来定义一个waker，它可以被 Task结构体来调度.
-impl ArcWake for Task {
-    fn wake_by_ref(arc_self: &Arc<Self>) {
-        // 调度Task来执行.执行器从channel中接收Task并poll Task.
-        let _ = arc_self.executor.send(arc_self.clone());
-    }
-}
+        // 1秒后关闭执行器, 不再等待还没有完成的任务
+        delay(Duration::from_secs(1)).await;
+        shutdown.shutdown();
+    });
 
 
-/// 一个基于channel的非常基础的futures 执行器(executor). 当任务(task)被唤醒时,它们通过在channel的发送方
-/// 中来排队调度. 执行器在接收方(receiver)等待并执行接收到的任务.
-///
-/// 当一个任务被执行时，channel的发送方(sender)传递任务的Waker.
-struct MiniTokio {
-    // 接收调度的任务. 当一个任务被安排(或调度)时, 与之相关的future会准备好推进. 这通常发生在资源任务准备执行操作的时候
-    // 比如说 一个socket接收到数据且一个 read 将调用成功时.
-    scheduled: channel::Receiver<Arc<Task>>,
-    sender: channel::Sender<Arc<Task>>,
+    // 启动mini-tokio 执行器循环，调度任务并接收执行结果. 所有任务完成或者 shutdown 被调用后返回
+    println!("execute MiniTokio run method!");
+    mini_tokio.run();
+    println!("MiniTokio shutdown");
 }
 
+// 用大量产生的小任务对比 channel 调度器与工作窃取调度器
+//
+// 每个 worker 上运行一个产生任务的任务, 它每次产生一批任务并等待它们全部完成.
+fn bench() {
+    const TASKS: usize = 400_000;
+    const BATCH: usize = 128;
+
+    println!("{:<14} {:>7} {:>12} {:>14}", "scheduler", "threads", "elapsed", "tasks/s");
+    for &threads in &[1, 2, 4, 8] {
+        for &scheduler in &[Scheduler::Channel, Scheduler::WorkStealing] {
+            let mut mini_tokio = MiniTokio::builder().worker_threads(threads).scheduler(scheduler).build().unwrap();
+            let done = Arc::new(AtomicUsize::new(0));
+
+            for _ in 0..threads {
+                let done = done.clone();
+                mini_tokio.spawn(async move {
+                    for _ in 0..TASKS / threads / BATCH {
+                        let handles: Vec<_> = (0..BATCH)
+                            .map(|i| {
+                                let done = done.clone();
+                                spawn(async move {
+                                    done.fetch_add(1, Ordering::Relaxed);
+                                    std::hint::black_box(i)
+                                })
+                            })
+                            .collect();
+                        for handle in handles {
+                            handle.await.unwrap();
+                        }
+                    }
+                });
+            }
 
-impl MiniTokio {
-    // 初始化一个新的mini-tokio实例
-    fn new() -> MiniTokio {
-        let (sender, scheduled) = channel::bounded(1000);
-        MiniTokio{scheduled, sender}
-    }
-
-    // 在mini-tokio实例上产生一个future
-    // 给future 包装task 并将其推送到 scheduled 队列中去,当run方法被调用时future将会执行
-    fn spawn<F>(&mut self, future: F)
-    where F:Future<Output = ()> + Send + 'static,
-    {
-        Task::spawn(future, &self.sender)
-    }
+            let start = Instant::now();
+            mini_tokio.run();
+            let elapsed = start.elapsed();
 
-    /// 运行执行器
-    ///
-    /// 这将启动执行器循环，并无限的运行，没有实现关机的机制
-    ///
-    /// 任务从 scheduled 通道的 receiver方出来. 在channel上接收一个任务表明任务已经准备好被执行了.
-    /// 这发生在任务首次被创建和任务被唤醒时.
-    fn run(&self) {
-        println!("execute MiniTokio run method!");
-
-        // 设置 CURRENT 线程局部变量来指向当前执行器
-        // tokio 使用一个thread local变量来实现 `tokio::spawn`.
-        CURRENT.with(|cell|{
-            *cell.borrow_mut() = Some(self.sender.clone());
-        });
-
-        while let Ok(task) = self.scheduled.recv() {
-            task.poll();
+            assert_eq!(done.load(Ordering::Relaxed), TASKS / threads / BATCH * threads * BATCH);
+            let rate = done.load(Ordering::Relaxed) as f64 / elapsed.as_secs_f64();
+            println!("{:<14} {:>7} {:>12.2?} {:>14.0}", format!("{:?}", scheduler), threads, elapsed, rate);
         }
     }
 }
 
+task_local! {
+    // 当前任务处理的连接的编号
+    static CONN_ID: usize;
+}
 
-// 异步等待，其作用相当于 thread::sleep. 尝试在当前函数上暂停指定的时间
-//
-// mini-tokio 通过一个计时器线程(timer thread) 来实现Delay, sleep 指定的duration后，一旦delay完成，就会
-// 通知调用者. 每次调用delay都会产生一个线程. 显然这不是一个好的实施策略，没有人会将这种方法用在生产上(这里只是为了示例tokio原理)
-// 真实的tokio没有使用这种策略.
-async fn delay(dur: Duration) {
-
-    // delay 在这里是一种片面的future描述. 有时候，它被当作一种 "resource"(资源). 其它的资源包括,socket与channels.
-    // resource 可能不是按 async/await 来实现的，因为它们必须与一些操作系统细节合并. 因为这一原因，我们必须手动来实现 future
-    //
-    // 不过，最好将API公共为一个 async fn . 一个有用的方式是，手动定义私有future,然后从公共(pub)的`async fn`中使用它的API.
-
-    struct Delay {
-        // delay什么时候完成
-        when: Instant,
-        // 一旦delay完成后就会通知waker. waker必须能被timer线程与future访问，所以它使用Arc<Mutex<>>来包装.
-        waker: Option<Arc<Mutex<Waker>>>,
-    }
-
-    // 为Delay 实现 Future trait
-    impl Future for Delay {
-        type Output = ();
-
-        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-            // 首先，如果第一次future就被调用，则产生一个timer线程. 如果timer线程已经在运行了，要确保存储的waker
-            // 能匹配上当前task的waker.
-            // 看是否能匹配上当前task的waker
-            if let Some(waker) = &self.waker {
-                let mut waker = waker.lock().unwrap();
+// 与 echo-server 相同, 只是运行在 mini-tokio 上, socket 由 mini-tokio 的 reactor 驱动
+fn echo() {
+    let mut mini_tokio = MiniTokio::builder().worker_threads(4).build().unwrap();
 
-                if !waker.will_wake(cx.waker()) {
-                    *waker = cx.waker().clone();
-                }
-            }else {
-                // 如果不能匹配上,创建一个waker
-                let when = self.when;
-                let waker = Arc::new(Mutex::new(cx.waker().clone()));
-                // 赋值给当前task的waker
-                self.waker = Some(waker.clone());
-
-                // 每一次poll 被调用，产生一个timer线程
-                thread::spawn(move ||{
-                   let now = Instant::now();
-                    // 如果还没到时间
-                    if now < when {
-                        // 睡眠一下剩余的时间
-                        thread::sleep(when - now);
+    mini_tokio.spawn(async {
+        // 绑定一个地址与端口
+        let listener = TcpListener::bind("127.0.0.1:6124").unwrap();
+        for conn_id in 0.. {
+            let (mut socket, _) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => return println!("accept error: {}", e),
+            };
+            // 连接的编号保存在任务局部变量中, 不用传递给处理连接的每一个函数
+            spawn(CONN_ID.scope(conn_id, async move {
+                // 缓存buffer 手动复制内容到writer中
+                let mut buf: Vec<u8> = vec![0; 32];
+                loop {
+                    match socket.read(&mut buf).await {
+                        // 如果是 Ok(0) 表示远程已经关闭链接, 那就直接return
+                        Ok(0) => return,
+                        Ok(n) => {
+                            log_received(&buf[..n]);
+                            if socket.write_all(&buf[..n]).await.is_err() {
+                                return;
+                            }
+                        }
+                        Err(_) => return,
                     }
-                    // 如果duration时间过去后，再通过激活waker来通知调用者
-                    let waker = waker.lock().unwrap();
-                    waker.wake_by_ref();
-                });
-            }
-
-            // 一旦waker被存储且timer线程已经开始时，此时就要来检查delay是否已经完成了. 这通过当前时间来检查.
-            // 如果duration时间已过，Future就完成了，此时返回Poll::Ready()
-            if Instant::now() >= self.when {
-                // 说明delay duration时间已经过去
-                Poll::Ready(()) // 返回Poll::Ready()
-            }else {
-                // 时间没过返回Poll::Pending
-                Poll::Pending
-            }
+                }
+            }));
         }
-    }
+    });
 
-    // 回到delay function中, 初始化一个Delay实全
-    let future = Delay {
-        when: Instant::now() + dur,
-        waker: None, // 初始时并没有waker，它由poll去创建
-    };
+    mini_tokio.run();
+}
 
-    // 等待duration完成
-    future.await;
+fn log_received(data: &[u8]) {
+    println!("Receive data: {:?} from client {}", String::from_utf8(Vec::from(data)), CONN_ID.get());
+}
+
+// drop 时打印一条消息, 用来观察任务的 future 是否被正确地释放
+struct DropGuard(&'static str);
+
+impl Drop for DropGuard {
+    fn drop(&mut self) {
+        println!("{} dropped", self.0);
+    }
 }
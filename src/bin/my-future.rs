@@ -1,13 +1,12 @@
-mod common;
 use std::time::{Instant, Duration};
-use common::delay::Delay;
+use tokio_cn_doc::mini_tokio::time::Delay;
 
 #[tokio::main]
 async fn main() {
     // when = 现在的时间戳+ 10ms
     let when = Instant::now() + Duration::from_millis(10);
     // 初始化一个Delay
-    let future = Delay{when};
+    let future = Delay::new(when);
 
     println!("Before future.await call");
 
@@ -1,3 +1,4 @@
+// 这个示例故意无法编译, 需要开启 send-bound 特性才会构建: cargo run --bin send-bound --features send-bound
 use tokio::task::yield_now;
 use std::rc::Rc;
 use std::sync::Arc;
@@ -25,6 +26,7 @@ async fn main() {
         yield_now().await;
 
         // rc 在　.await之后还在使用, 它必须被保存在　task 的　状态中，这里没有保存所以不行
+        // 需要这样使用 rc 时, 可以用 mini-tokio 的 LocalSet 与 spawn_local 在当前线程上执行任务, 见 mini-tokio 示例
         println!("{}", rc);
     });
 }
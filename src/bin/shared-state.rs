@@ -1,12 +1,8 @@
 use tokio::net::{TcpListener, TcpStream};
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
-use bytes::Bytes;
-use mini_redis::{Connection, Frame, Command};
+use tokio_cn_doc::relational::connection::Connection;
+use tokio_cn_doc::relational::cmd::Command;
+use tokio_cn_doc::relational::db::{self, Db};
 use std::option::Option::Some;
-use mini_redis::cmd::Command::{Set, Get};
-
-type Db = Arc<Mutex<HashMap<String, Bytes>>>;
 
 #[tokio::main]
 async fn main() -> std::io::Result<()>{
@@ -14,7 +10,7 @@ async fn main() -> std::io::Result<()>{
     let mut listener = TcpListener::bind("127.0.0.1:6379").await?;
     println!("Listening localhost and port 6379");
 
-    let db = Arc::new(Mutex::new(HashMap::new()));
+    let db = db::new_db();
 
     loop {
         let (socket, _) = listener.accept().await?;
@@ -28,28 +24,22 @@ async fn main() -> std::io::Result<()>{
 }
 
 /// 处理函数
+///
+/// 支持流水线: 一次取出缓冲区中所有完整的命令, 按顺序执行, 所有回复缓冲后只 flush 一次
 async fn process(socket: TcpStream, db: Db) {
     let mut connection = Connection::new(socket);
 
-    while let Some(frame) = connection.read_frame().await.unwrap() {
-        let response  = match Command::from_frame(frame).unwrap() {
-            Set(cmd) => {
-                let mut db = db.lock().unwrap();
-                db.insert(cmd.key().to_string(), cmd.value().clone());
-                // 返回 frame
-                Frame::Simple("OK".to_string())
-            }
-            Get(cmd) => {
-                let db = db.lock().unwrap();
-                if let Some(value) = db.get(cmd.key()) {
-                    Frame::Bulk(value.clone())
-                }else {
-                    Frame::Null
-                }
+    while let Some(frames) = connection.read_pipeline().await.unwrap() {
+        for frame in frames {
+            // HELLO 切换协议版本, 它的回复写在之前排队的回复后面, 与它们一起被 flush
+            if connection.handle_hello(&frame).await.unwrap() {
+                continue;
             }
-            // 其它cmd 情况
-            cmd=> panic!("unimplemented Command :{:?}", cmd),
-        };
-        connection.write_frame(&response).await.unwrap();
+            // 命令的执行逻辑在 relational::db 中, 这样可以在 mini-tokio 的模拟模式下测试
+            let response = db::execute(&db, Command::from_frame(frame).unwrap());
+            connection.queue_frame(&response).unwrap();
+        }
+        // 一次写出这一批命令的所有回复
+        connection.flush().await.unwrap();
     }
 }
\ No newline at end of file
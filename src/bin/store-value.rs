@@ -1,35 +1,45 @@
 use tokio::net::{TcpStream, TcpListener};
-use mini_redis::{Connection, Frame};
+use tokio_cn_doc::relational::connection::Connection;
+use tokio_cn_doc::relational::frame_enum::Frame;
 
 async fn process(socket: TcpStream) {
-    use mini_redis::Command::{self, Get, Set};
+    use tokio_cn_doc::relational::cmd::Command::{self, Get, Set};
     use std::collections::HashMap;
 
     // 声明一个用来存储数据的hashmap
     let mut db = HashMap::new();
 
-    // 此connection 由mini_redis包提供,　可以处理socket中的　帧
+    // 此connection 由 relational 模块提供,　可以处理socket中的　帧
     let mut connection = Connection::new(socket);
 
-    while let Some(frame) = connection.read_frame().await.unwrap() {
-        let response = match Command::from_frame(frame).unwrap() {
-            Set(cmd) => {
-                db.insert(cmd.key().to_string(), cmd.value().clone());
-                Frame::Simple("OK".to_string())
+    // 客户端可以不等回复就连续发送多个命令(流水线), 每次取出缓冲区中所有完整的命令一起处理
+    while let Some(frames) = connection.read_pipeline().await.unwrap() {
+        for frame in frames {
+            // HELLO 切换协议版本, 它的回复写在之前排队的回复后面, 与它们一起被 flush
+            if connection.handle_hello(&frame).await.unwrap() {
+                continue;
             }
-            Get(cmd) => {
-                if let Some(value) = db.get(cmd.key()) {
-                    // Frame::Bulk() 里面要是一个Bytes 使用 into() 转换为 Bytes
-                    Frame::Bulk(value.clone().into())
-                }else {
-                    Frame::Null
+            let response = match Command::from_frame(frame).unwrap() {
+                Set(cmd) => {
+                    db.insert(cmd.key().to_string(), cmd.value().clone());
+                    Frame::Simple("OK".to_string())
                 }
-            }
-            // 其它的命令没有实现
-            cmd=> panic!("unimplemented {:?}", cmd),
-        };
-        // 写入响应到客户端
-        connection.write_frame(&response).await.unwrap();
+                Get(cmd) => {
+                    if let Some(value) = db.get(cmd.key()) {
+                        // clone 一个 Bytes 只增加引用计数
+                        Frame::Bulk(value.clone())
+                    }else {
+                        Frame::Null
+                    }
+                }
+                // 其它的命令没有实现
+                cmd=> panic!("unimplemented {:?}", cmd),
+            };
+            // 回复先放入写缓冲区
+            connection.queue_frame(&response).unwrap();
+        }
+        // 写入这一批命令的所有响应到客户端
+        connection.flush().await.unwrap();
     }
 }
 
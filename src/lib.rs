@@ -0,0 +1,3 @@
+//! 基于官方指南示例代码整理出的可复用模块, 可以在 `src/bin` 下的示例中通过 `tokio_cn_doc::` 路径使用.
+pub mod mini_tokio;
+pub mod relational;
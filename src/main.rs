@@ -1,5 +1,4 @@
 mod tools;
-mod relational;
 
 fn main() {
     println!("Welcome Tokio CN doc and demo!");
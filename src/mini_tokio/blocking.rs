@@ -0,0 +1,129 @@
+//! 执行阻塞操作的线程池, 由 `spawn_blocking` 使用.
+//!
+//! 线程按需创建, 最多 `max_threads` 个. 空闲超过 `keep_alive` 的线程自动退出, 所以没有阻塞任务时线程池不占用线程.
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// 线程池中执行的任务
+pub(super) type Job = Box<dyn FnOnce() + Send>;
+
+pub(super) struct BlockingPool {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    state: Mutex<State>,
+    // 有新任务或者线程池关闭时通知空闲的线程
+    condvar: Condvar,
+    // 最多的线程数
+    max_threads: usize,
+    // 线程空闲多久后退出
+    keep_alive: Duration,
+    // 用来给线程命名
+    next_id: AtomicUsize,
+}
+
+struct State {
+    // 等待执行的任务
+    queue: VecDeque<Job>,
+    // 当前的线程数
+    num_threads: usize,
+    // 在 condvar 上等待任务的线程数
+    num_idle: usize,
+    // 已经通知但还没有醒来的空闲线程数, 避免多个任务通知同一个线程
+    num_notify: usize,
+    shutdown: bool,
+}
+
+impl BlockingPool {
+    pub(super) fn new(max_threads: usize, keep_alive: Duration) -> BlockingPool {
+        BlockingPool {
+            inner: Arc::new(Inner {
+                state: Mutex::new(State {
+                    queue: VecDeque::new(),
+                    num_threads: 0,
+                    num_idle: 0,
+                    num_notify: 0,
+                    shutdown: false,
+                }),
+                condvar: Condvar::new(),
+                max_threads,
+                keep_alive,
+                next_id: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    // 把任务放入队列. 有空闲的线程时唤醒一个, 否则在没有达到上限时创建一个新线程,
+    // 达到上限时任务在队列中等待某个线程执行完手上的任务
+    pub(super) fn spawn(&self, job: Job) {
+        let mut state = self.inner.state.lock().unwrap();
+        if state.shutdown {
+            // 线程池已经关闭, drop 任务, JoinHandle 会得到取消的结果
+            return;
+        }
+        state.queue.push_back(job);
+
+        if state.num_idle > state.num_notify {
+            state.num_notify += 1;
+            self.inner.condvar.notify_one();
+        } else if state.num_threads < self.inner.max_threads {
+            state.num_threads += 1;
+            let inner = self.inner.clone();
+            let id = inner.next_id.fetch_add(1, Ordering::Relaxed);
+            let spawned = thread::Builder::new()
+                .name(format!("mini-tokio-blocking-{}", id))
+                .spawn(move || inner.run());
+            if spawned.is_err() {
+                // 创建线程失败时任务留在队列中, 由已有的线程执行
+                state.num_threads -= 1;
+            }
+        }
+    }
+
+    // 关闭线程池: 还没有开始执行的任务被 drop, 空闲的线程立即退出, 正在执行任务的线程执行完后退出
+    pub(super) fn shutdown(&self) {
+        let queue = {
+            let mut state = self.inner.state.lock().unwrap();
+            state.shutdown = true;
+            std::mem::take(&mut state.queue)
+        };
+        self.inner.condvar.notify_all();
+        // 在锁外 drop 任务, 它们的析构函数会唤醒等待的任务
+        drop(queue);
+    }
+}
+
+impl Inner {
+    // 线程循环, 执行队列中的任务, 空闲超过 keep_alive 后退出
+    fn run(&self) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(job) = state.queue.pop_front() {
+                drop(state);
+                // 任务中的 panic 已经在 spawn_blocking 中捕获了, 这里只是防止 panic 钩子的 panic 让线程计数出错
+                let _ = panic::catch_unwind(AssertUnwindSafe(job));
+                state = self.state.lock().unwrap();
+                continue;
+            }
+            if state.shutdown {
+                break;
+            }
+
+            state.num_idle += 1;
+            let (guard, timeout) = self.condvar.wait_timeout(state, self.keep_alive).unwrap();
+            state = guard;
+            state.num_idle -= 1;
+            if state.num_notify > 0 {
+                state.num_notify -= 1;
+            } else if timeout.timed_out() && state.queue.is_empty() {
+                break;
+            }
+        }
+        state.num_threads -= 1;
+    }
+}
@@ -0,0 +1,72 @@
+//! 协作式调度的预算.
+//!
+//! 任务每次被 poll 时得到一份预算, 计时器, socket, `JoinHandle` 这些叶子 future 每次被 poll 消耗一份.
+//! 预算用完后它们即使已经就绪也返回 `Pending` 并立即唤醒当前任务, 任务被放到调度队列的末尾, 其它任务得以执行.
+//! 否则一个不停地等待已经就绪的 future 的循环 (比如不断从有数据的 channel 中读取) 永远不会返回 `Pending`,
+//! 会一直占着 worker. 与 tokio 的 coop 模块相同.
+//!
+//! 自己实现的叶子 future (比如 channel) 可以调用 `poll_proceed` 参与预算, 任务也可以用 `yield_now` 主动让出 worker.
+use std::cell::Cell;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+// 每次 poll 任务时的预算, 数值与 tokio 相同
+const BUDGET: u8 = 128;
+
+thread_local! {
+    // 当前任务剩余的预算, None 表示不在执行器中, 不受预算限制
+    static CURRENT: Cell<Option<u8>> = const { Cell::new(None) };
+}
+
+// 在一份新的预算下执行 `f`, 执行器 poll 任务时使用. 返回 (包括 panic) 后恢复原来的预算
+pub(super) fn budget<R>(f: impl FnOnce() -> R) -> R {
+    struct Reset(Option<u8>);
+
+    impl Drop for Reset {
+        fn drop(&mut self) {
+            CURRENT.with(|cell| cell.set(self.0));
+        }
+    }
+
+    let _reset = Reset(CURRENT.with(|cell| cell.replace(Some(BUDGET))));
+    f()
+}
+
+/// 消耗一份预算. 预算已经用完时唤醒当前任务并返回 `Pending`, 叶子 future 收到 `Pending` 时应当直接返回 `Pending`
+pub fn poll_proceed(cx: &mut Context<'_>) -> Poll<()> {
+    CURRENT.with(|cell| match cell.get() {
+        Some(0) => {
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+        Some(n) => {
+            cell.set(Some(n - 1));
+            Poll::Ready(())
+        }
+        None => Poll::Ready(()),
+    })
+}
+
+/// 让出 worker, 当前任务被放到调度队列的末尾, 等其它已经就绪的任务执行后再继续. 与 tokio::task::yield_now() 一样
+pub async fn yield_now() {
+    YieldNow { yielded: false }.await
+}
+
+struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
@@ -0,0 +1,149 @@
+//! 任务的 `JoinHandle` 与 `JoinError`.
+use std::any::Any;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll, Waker};
+
+use super::coop;
+
+/// 等待任务完成的句柄, 它本身是一个 future, 任务完成后返回任务的输出
+///
+/// 任务 panic 或者被取消时返回 `JoinError`.
+pub struct JoinHandle<T> {
+    pub(super) state: Arc<Mutex<JoinState<T>>>,
+    // 用来取消任务, spawn_blocking 产生的任务无法取消, 这里为 None
+    pub(super) task: Option<Arc<dyn Abort>>,
+}
+
+// 可以被 JoinHandle 取消的任务, 执行器的任务与 LocalSet 的任务各自实现
+pub(super) trait Abort: Send + Sync {
+    // 标记任务被取消, 并唤醒它让执行器 drop 它的 future
+    fn abort(self: Arc<Self>);
+}
+
+// 任务与 JoinHandle 之间共享的状态
+pub(super) struct JoinState<T> {
+    // 任务的结果, 被 JoinHandle 取走后变为 None
+    pub(super) output: Option<Result<T, JoinError>>,
+    // 等待结果的 JoinHandle 的 waker
+    pub(super) waker: Option<Waker>,
+}
+
+impl<T> JoinHandle<T> {
+    /// 取消任务. 任务的 future 会在下一次被调度时 drop, 等待 JoinHandle 会得到一个 `JoinError::is_cancelled` 的错误.
+    ///
+    /// 已经完成的任务不受影响. `spawn_blocking` 产生的任务无法取消, 对它调用 `abort` 没有效果.
+    pub fn abort(&self) {
+        if let Some(task) = &self.task {
+            task.clone().abort();
+        }
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        ready!(coop::poll_proceed(cx));
+
+        let mut state = self.state.lock().unwrap();
+
+        match state.output.take() {
+            Some(output) => Poll::Ready(output),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+// 任务完成时把结果交给 JoinHandle. 如果任务的 future 还没有完成就被 drop 了 (取消或者执行器关闭),
+// 在析构函数中把结果设置为已取消, 这样等待它的 JoinHandle 不会永远挂起.
+pub(super) struct Completer<T> {
+    pub(super) state: Arc<Mutex<JoinState<T>>>,
+    pub(super) done: bool,
+}
+
+impl<T> Completer<T> {
+    pub(super) fn complete(&mut self, output: Result<T, JoinError>) {
+        let mut state = self.state.lock().unwrap();
+        state.output = Some(output);
+        self.done = true;
+
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Drop for Completer<T> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.complete(Err(JoinError::Cancelled));
+        }
+    }
+}
+
+/// 任务没有正常完成的原因
+pub enum JoinError {
+    /// 任务被 `JoinHandle::abort` 取消, 或者执行器关闭时任务还没有完成
+    Cancelled,
+    /// 任务 panic 了, 保存了 panic 的参数
+    Panic(Box<dyn Any + Send + 'static>),
+}
+
+impl JoinError {
+    pub fn is_cancelled(&self) -> bool {
+        matches!(self, JoinError::Cancelled)
+    }
+
+    pub fn is_panic(&self) -> bool {
+        matches!(self, JoinError::Panic(_))
+    }
+
+    /// 取出 panic 的参数, 可以用 `std::panic::resume_unwind` 在当前任务中继续 panic
+    ///
+    /// # Panics
+    ///
+    /// 如果错误不是 panic 则 panic
+    pub fn into_panic(self) -> Box<dyn Any + Send + 'static> {
+        match self {
+            JoinError::Panic(payload) => payload,
+            JoinError::Cancelled => panic!("`JoinError` reason is not a panic"),
+        }
+    }
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JoinError::Cancelled => "task was cancelled".fmt(fmt),
+            JoinError::Panic(payload) => match panic_message(payload) {
+                Some(msg) => write!(fmt, "task panicked: {}", msg),
+                None => "task panicked".fmt(fmt),
+            },
+        }
+    }
+}
+
+impl fmt::Debug for JoinError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JoinError::Cancelled => "Cancelled".fmt(fmt),
+            JoinError::Panic(payload) => write!(fmt, "Panic({:?})", panic_message(payload).unwrap_or("..")),
+        }
+    }
+}
+
+impl std::error::Error for JoinError {}
+
+/// 取出 panic 的消息, panic 的参数通常是 &str 或者 String
+pub fn panic_message(payload: &(dyn Any + Send)) -> Option<&str> {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+}
@@ -0,0 +1,250 @@
+//! 在当前线程上执行 `!Send` 的 future 的 `LocalSet`.
+//!
+//! `tokio::spawn` 与 `mini_tokio::spawn` 产生的任务可能在任意一个 worker 上执行, 所以要求 future 是 `Send` 的,
+//! 在 `.await` 之后还要使用 `Rc` 这样的值的 future 无法被产生 (见 send-bound 示例). `LocalSet` 中的任务只在
+//! 运行 `LocalSet` 的线程上执行, 不需要 `Send`. 与 tokio::task::LocalSet 一样.
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::future::{self, Future};
+use std::mem;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use futures::task::{self, ArcWake};
+use futures::FutureExt;
+
+use super::coop;
+use super::join::{Abort, Completer, JoinError, JoinHandle, JoinState};
+use super::runtime;
+use super::MiniTokio;
+
+// 每次 poll LocalSet 时最多执行的任务数, 超过后让出 worker, 避免 LocalSet 占着 worker 不放
+const MAX_TASKS_PER_TICK: usize = 61;
+
+thread_local! {
+    // 正在运行的 LocalSet, spawn_local 通过它找到 LocalSet
+    static CURRENT: RefCell<Option<Rc<Shared>>> = const { RefCell::new(None) };
+}
+
+/// 在 `LocalSet` 中产生一个任务, 任务的 future 不需要是 `Send` 的. 与 tokio::task::spawn_local() 一样
+///
+/// # Panics
+///
+/// 如果不是在 `LocalSet::run_until` 或者 `LocalSet::block_on` 中调用则 panic
+pub fn spawn_local<F>(future: F) -> JoinHandle<F::Output>
+where F: Future + 'static,
+      F::Output: 'static,
+{
+    CURRENT.with(|cell| {
+        let borrow = cell.borrow();
+        let shared = borrow.as_ref().expect("`spawn_local` called from outside of a `LocalSet`");
+        shared.spawn(future)
+    })
+}
+
+/// 一组在同一个线程上执行的任务
+///
+/// `LocalSet` 本身不是 `Send` 的, 它通过 `run_until` 嵌入到当前线程上运行的 future 中 (通常是 `MiniTokio::block_on`),
+/// 在这个 future 被 poll 时执行其中的任务. drop `LocalSet` 时还没有完成的任务被 drop.
+pub struct LocalSet {
+    shared: Rc<Shared>,
+}
+
+struct Shared {
+    // 还没有完成的任务的 future, 按 id 索引
+    tasks: RefCell<HashMap<usize, LocalFuture>>,
+    next_id: Cell<usize>,
+    // 被唤醒的任务, waker 可能在其它线程上被调用, 所以这部分状态是 Send 的
+    queue: Arc<Queue>,
+}
+
+// LocalSet 中的任务的 future, 输出已经交给了 JoinHandle
+type LocalFuture = Pin<Box<dyn Future<Output = ()>>>;
+
+struct Queue {
+    ready: Mutex<VecDeque<Arc<Header>>>,
+    // 运行 LocalSet 的 future 的 waker, 有任务被唤醒时唤醒它
+    waker: Mutex<Option<Waker>>,
+}
+
+// 任务中 Send 的部分, 用作任务的 waker 与 JoinHandle 取消任务的句柄
+struct Header {
+    id: usize,
+    queue: Arc<Queue>,
+    // 已经在 ready 队列中, 重复的唤醒不再入队
+    scheduled: AtomicBool,
+    aborted: AtomicBool,
+}
+
+impl LocalSet {
+    pub fn new() -> LocalSet {
+        LocalSet {
+            shared: Rc::new(Shared {
+                tasks: RefCell::new(HashMap::new()),
+                next_id: Cell::new(0),
+                queue: Arc::new(Queue { ready: Mutex::new(VecDeque::new()), waker: Mutex::new(None) }),
+            }),
+        }
+    }
+
+    /// 在 `LocalSet` 中产生一个任务, 它在 `LocalSet` 运行时执行
+    pub fn spawn_local<F>(&self, future: F) -> JoinHandle<F::Output>
+    where F: Future + 'static,
+          F::Output: 'static,
+    {
+        self.shared.spawn(future)
+    }
+
+    /// 运行 `future` 直到完成, 期间执行 `LocalSet` 中的任务. `future` 与任务中都可以调用 `spawn_local`
+    ///
+    /// `future` 完成时还没有完成的任务留在 `LocalSet` 中, 下一次运行时继续执行.
+    pub async fn run_until<F: Future>(&self, future: F) -> F::Output {
+        let mut future = std::pin::pin!(future);
+
+        future::poll_fn(|cx| {
+            self.enter(|| {
+                *self.shared.queue.waker.lock().unwrap() = Some(cx.waker().clone());
+
+                if let Poll::Ready(output) = future.as_mut().poll(cx) {
+                    return Poll::Ready(output);
+                }
+                // 还有被唤醒的任务没有执行, 让出 worker 后再回来执行它们
+                if self.shared.tick() {
+                    cx.waker().wake_by_ref();
+                }
+                Poll::Pending
+            })
+        })
+        .await
+    }
+
+    /// 在 `mini_tokio` 上运行 `future` 直到完成, 期间在当前线程上执行 `LocalSet` 中的任务
+    pub fn block_on<F: Future>(&self, mini_tokio: &MiniTokio, future: F) -> F::Output {
+        mini_tokio.block_on(self.run_until(future))
+    }
+
+    // 设置 CURRENT, 这样在 `f` 中可以调用 spawn_local. 嵌套的 LocalSet 返回时恢复外层的 LocalSet
+    fn enter<R>(&self, f: impl FnOnce() -> R) -> R {
+        struct Reset(Option<Rc<Shared>>);
+
+        impl Drop for Reset {
+            fn drop(&mut self) {
+                CURRENT.with(|cell| *cell.borrow_mut() = self.0.take());
+            }
+        }
+
+        let _reset = Reset(CURRENT.with(|cell| cell.borrow_mut().replace(self.shared.clone())));
+        f()
+    }
+}
+
+impl Default for LocalSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shared {
+    // 包装 future, 捕获其中的 panic 并把结果交给 JoinHandle, 然后登记并唤醒任务. 与执行器中的任务一样, panic 先交给
+    // 运行 LocalSet 的执行器的钩子
+    fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where F: Future + 'static,
+          F::Output: 'static,
+    {
+        let state = Arc::new(Mutex::new(JoinState { output: None, waker: None }));
+        let mut completer = Completer { state: state.clone(), done: false };
+
+        let id = self.next_id.get();
+        self.next_id.set(id + 1);
+        let header = Arc::new(Header {
+            id,
+            queue: self.queue.clone(),
+            scheduled: AtomicBool::new(false),
+            aborted: AtomicBool::new(false),
+        });
+
+        let future = async move {
+            match AssertUnwindSafe(future).catch_unwind().await {
+                Ok(output) => completer.complete(Ok(output)),
+                Err(payload) => {
+                    runtime::on_task_panic(&*payload);
+                    completer.complete(Err(JoinError::Panic(payload)));
+                }
+            }
+        };
+        self.tasks.borrow_mut().insert(id, Box::pin(future));
+        ArcWake::wake_by_ref(&header);
+
+        JoinHandle { state, task: Some(header) }
+    }
+
+    // 执行被唤醒的任务, 返回是否还有被唤醒的任务没有执行
+    fn tick(&self) -> bool {
+        for _ in 0..MAX_TASKS_PER_TICK {
+            let header = match self.queue.ready.lock().unwrap().pop_front() {
+                Some(header) => header,
+                None => return false,
+            };
+            // 在 poll 之前清除标记, poll 的过程中被唤醒的任务会再次入队
+            header.scheduled.store(false, Ordering::SeqCst);
+
+            // poll 时把任务从登记表中取出来, 这样任务中可以调用 spawn_local. 已经完成的任务不在登记表中
+            let mut future = match self.tasks.borrow_mut().remove(&header.id) {
+                Some(future) => future,
+                None => continue,
+            };
+            // 被取消的任务直接 drop, JoinHandle 会得到取消的结果
+            if header.aborted.load(Ordering::SeqCst) {
+                continue;
+            }
+
+            let waker = task::waker(header.clone());
+            let mut cx = Context::from_waker(&waker);
+            if coop::budget(|| future.as_mut().poll(&mut cx)).is_pending() {
+                self.tasks.borrow_mut().insert(header.id, future);
+            }
+        }
+        !self.queue.ready.lock().unwrap().is_empty()
+    }
+}
+
+impl ArcWake for Header {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        if arc_self.scheduled.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        arc_self.queue.ready.lock().unwrap().push_back(arc_self.clone());
+        let waker = arc_self.queue.waker.lock().unwrap().clone();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl Abort for Header {
+    fn abort(self: Arc<Self>) {
+        self.aborted.store(true, Ordering::SeqCst);
+        ArcWake::wake_by_ref(&self);
+    }
+}
+
+// ready 队列中的 Header 引用了队列本身, drop 时清空队列来打破循环引用
+impl Drop for LocalSet {
+    fn drop(&mut self) {
+        // 任务的析构函数中可能又产生了新的任务, 循环直到没有任务为止
+        loop {
+            let tasks = mem::take(&mut *self.shared.tasks.borrow_mut());
+            if tasks.is_empty() {
+                break;
+            }
+            self.enter(|| drop(tasks));
+        }
+        self.shared.queue.ready.lock().unwrap().clear();
+        self.shared.queue.waker.lock().unwrap().take();
+    }
+}
@@ -0,0 +1,29 @@
+//! 官方指南中的 mini-tokio, 一个非常基础的异步Rust执行器.
+//!
+//! 原代码链接 [mini-tokio](https://github.com/tokio-rs/website/blob/master/tutorial-code/mini-tokio/src/main.rs).
+//! 在此基础上逐步加入了 `JoinHandle`, 多线程的工作窃取调度器, 共享的时间轮, 基于 epoll 的 reactor,
+//! 执行阻塞操作的线程池, 协作式调度的预算, 执行 `!Send` 任务的 `LocalSet` 与任务局部变量,
+//! 可重复的模拟模式等功能.
+//! `stage_one` 与 `stage_two` 保留了指南中前两个阶段的实现, 分别由 `mini-tokio-one` 与 `mini-tokio-two` 两个示例使用,
+//! 完整的执行器是 `MiniTokio`, 由 `mini-tokio` 示例使用.
+mod blocking;
+mod join;
+mod local;
+mod runtime;
+mod scheduler;
+mod task;
+
+pub mod coop;
+pub mod net;
+pub mod reactor;
+pub mod sim;
+pub mod stage_one;
+pub mod stage_two;
+pub mod task_local;
+pub mod time;
+
+pub use coop::yield_now;
+pub use join::{panic_message, JoinError, JoinHandle};
+pub use local::{spawn_local, LocalSet};
+pub use runtime::{spawn, spawn_blocking, Builder, MiniTokio, Shutdown};
+pub use scheduler::Scheduler;
@@ -0,0 +1,133 @@
+//! 注册到 reactor 中的异步 TCP socket, 用法与 `tokio::net` 相同.
+//!
+//! 读写实现了 futures 的 `AsyncRead` 与 `AsyncWrite`, 可以配合 `AsyncReadExt`/`AsyncWriteExt` 使用.
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, ToSocketAddrs};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::future::poll_fn;
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::ready;
+
+use super::reactor::{Direction, Registration};
+
+/// 异步的 TCP 监听 socket
+pub struct TcpListener {
+    io: mio::net::TcpListener,
+    registration: Registration,
+}
+
+impl TcpListener {
+    /// 绑定到指定的地址. 如果地址解析出多个结果, 依次尝试直到成功
+    ///
+    /// # Panics
+    ///
+    /// 不在 mini-tokio 的任务中调用时 panic
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<TcpListener> {
+        each_addr(addr, |addr| {
+            let mut io = mio::net::TcpListener::bind(addr)?;
+            let registration = Registration::new(&mut io)?;
+            Ok(TcpListener { io, registration })
+        })
+    }
+
+    /// 等待并接受一个新的连接
+    pub async fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        poll_fn(|cx| self.poll_accept(cx)).await
+    }
+
+    pub fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<io::Result<(TcpStream, SocketAddr)>> {
+        let (io, addr) = ready!(self.registration.poll_io(cx, Direction::Read, || self.io.accept()))?;
+        Poll::Ready(TcpStream::new(io).map(|stream| (stream, addr)))
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.io.local_addr()
+    }
+}
+
+/// 异步的 TCP 连接
+pub struct TcpStream {
+    io: mio::net::TcpStream,
+    registration: Registration,
+}
+
+impl TcpStream {
+    /// 连接到指定的地址. 如果地址解析出多个结果, 依次尝试直到成功
+    pub async fn connect(addr: impl ToSocketAddrs) -> io::Result<TcpStream> {
+        let mut last_err = None;
+        for addr in addr.to_socket_addrs()? {
+            match TcpStream::connect_addr(addr).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err.unwrap_or_else(no_addresses))
+    }
+
+    async fn connect_addr(addr: SocketAddr) -> io::Result<TcpStream> {
+        let stream = TcpStream::new(mio::net::TcpStream::connect(addr)?)?;
+
+        // 非阻塞的 connect 在连接建立 (或者失败) 后 socket 变为可写
+        poll_fn(|cx| stream.registration.poll_ready(cx, Direction::Write)).await;
+        if let Some(e) = stream.io.take_error()? {
+            return Err(e);
+        }
+        // 连接失败时可能没有设置 SO_ERROR, peer_addr 返回 ENOTCONN
+        stream.io.peer_addr()?;
+        Ok(stream)
+    }
+
+    fn new(mut io: mio::net::TcpStream) -> io::Result<TcpStream> {
+        let registration = Registration::new(&mut io)?;
+        Ok(TcpStream { io, registration })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.io.local_addr()
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.io.peer_addr()
+    }
+}
+
+impl AsyncRead for TcpStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let me = self.get_mut();
+        me.registration.poll_io(cx, Direction::Read, || (&me.io).read(buf))
+    }
+}
+
+impl AsyncWrite for TcpStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let me = self.get_mut();
+        me.registration.poll_io(cx, Direction::Write, || (&me.io).write(buf))
+    }
+
+    // 写入直接进入内核的发送缓冲区, 不需要 flush
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(self.io.shutdown(Shutdown::Write))
+    }
+}
+
+// 依次对解析出的每个地址调用 f, 返回第一个成功的结果或者最后一个错误
+fn each_addr<T>(addr: impl ToSocketAddrs, mut f: impl FnMut(SocketAddr) -> io::Result<T>) -> io::Result<T> {
+    let mut last_err = None;
+    for addr in addr.to_socket_addrs()? {
+        match f(addr) {
+            Ok(value) => return Ok(value),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err.unwrap_or_else(no_addresses))
+}
+
+fn no_addresses() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "could not resolve to any addresses")
+}
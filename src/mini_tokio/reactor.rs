@@ -0,0 +1,276 @@
+//! 基于 epoll (通过 mio) 的 I/O reactor.
+//!
+//! `Registration` 是公开的, 可以用它实现其它的异步 I/O 类型, 用法参考 `net` 模块.
+//!
+//! socket 被设置为非阻塞的, 并以边缘触发的方式注册到 epoll 中. 读写返回 `WouldBlock` 时, 任务把自己的 waker 保存在
+//! socket 的 `Registration` 中然后返回 `Pending`. 执行器空闲时由一个 worker 在 `epoll_wait` 上等待, 收到事件后
+//! 设置 socket 的就绪状态并唤醒等待它的任务. 其它线程可以通过 `Reactor::unpark` 唤醒等待中的 worker.
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll, Waker};
+use std::time::Duration;
+
+use mio::event::Source;
+use mio::{Events, Interest, Token};
+
+use super::coop;
+
+// 用于唤醒 epoll_wait 的 token, 不会分配给 socket
+const WAKE_TOKEN: Token = Token(usize::MAX);
+
+// 就绪状态的低两位记录读写是否就绪, 其余的位是每次收到事件时递增的计数
+const READABLE: usize = 0b01;
+const WRITABLE: usize = 0b10;
+const TICK_SHIFT: usize = 2;
+
+thread_local! {
+    // 当前线程上运行的执行器的 reactor, 创建 socket 时用它来注册
+    static CURRENT: RefCell<Option<Arc<Reactor>>> = const { RefCell::new(None) };
+}
+
+/// 设置当前线程使用的 reactor, 执行器的 worker 线程启动时调用, 传入 None 清除
+pub(super) fn enter(reactor: Option<Arc<Reactor>>) {
+    CURRENT.with(|current| *current.borrow_mut() = reactor);
+}
+
+fn current() -> Arc<Reactor> {
+    CURRENT.with(|current| {
+        current
+            .borrow()
+            .clone()
+            .expect("there is no reactor running, must be called from the context of a mini-tokio runtime")
+    })
+}
+
+/// I/O 事件的驱动器
+pub(super) struct Reactor {
+    registry: mio::Registry,
+    // 通过 eventfd 唤醒 epoll_wait
+    waker: mio::Waker,
+    // 同一时间只有一个线程在 epoll_wait 上等待
+    driver: Mutex<Driver>,
+    // 所有注册了的 socket
+    sources: Mutex<HashMap<Token, Arc<ScheduledIo>>>,
+    next_token: AtomicUsize,
+}
+
+struct Driver {
+    poll: mio::Poll,
+    events: Events,
+}
+
+// socket 的就绪状态与等待它的任务, 由 reactor 与 `Registration` 共享
+struct ScheduledIo {
+    readiness: AtomicUsize,
+    waiters: Mutex<Waiters>,
+}
+
+#[derive(Default)]
+struct Waiters {
+    reader: Option<Waker>,
+    writer: Option<Waker>,
+}
+
+/// 读或者写
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Read,
+    Write,
+}
+
+impl Direction {
+    fn mask(self) -> usize {
+        match self {
+            Direction::Read => READABLE,
+            Direction::Write => WRITABLE,
+        }
+    }
+}
+
+impl Reactor {
+    pub(super) fn new() -> io::Result<Reactor> {
+        let poll = mio::Poll::new()?;
+        let registry = poll.registry().try_clone()?;
+        let waker = mio::Waker::new(&registry, WAKE_TOKEN)?;
+
+        Ok(Reactor {
+            registry,
+            waker,
+            driver: Mutex::new(Driver { poll, events: Events::with_capacity(1024) }),
+            sources: Mutex::new(HashMap::new()),
+            next_token: AtomicUsize::new(0),
+        })
+    }
+
+    /// 在 epoll_wait 上等待 I/O 事件, 唤醒就绪的 socket 上等待的任务. `timeout` 为 None 时一直等待到有事件或者被 `unpark`
+    pub(super) fn turn(&self, timeout: Option<Duration>) {
+        let mut driver = self.driver.lock().unwrap();
+        self.dispatch(&mut driver, timeout);
+    }
+
+    /// 与 `turn` 相同, 但是有其它线程在等待 I/O 事件时直接返回 false
+    pub(super) fn try_turn(&self, timeout: Option<Duration>) -> bool {
+        match self.driver.try_lock() {
+            Ok(mut driver) => {
+                self.dispatch(&mut driver, timeout);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// 唤醒在 `turn` 中等待的线程. 如果现在没有线程在等待, 下一次 `turn` 会立即返回
+    pub(super) fn unpark(&self) {
+        self.waker.wake().expect("failed to wake the reactor");
+    }
+
+    fn dispatch(&self, driver: &mut Driver, timeout: Option<Duration>) {
+        let Driver { poll, events } = driver;
+        match poll.poll(events, timeout) {
+            Ok(()) => {}
+            // 被信号打断, 就当作被唤醒了
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => return,
+            Err(e) => panic!("unexpected error when polling the I/O driver: {:?}", e),
+        }
+
+        for event in events.iter() {
+            if event.token() == WAKE_TOKEN {
+                continue;
+            }
+
+            let mut ready = 0;
+            // 对端关闭或者出错时也要唤醒任务, 让它在读写时得到 EOF 或者错误
+            if event.is_readable() || event.is_read_closed() || event.is_error() {
+                ready |= READABLE;
+            }
+            if event.is_writable() || event.is_write_closed() || event.is_error() {
+                ready |= WRITABLE;
+            }
+
+            // socket 可能已经被 drop 了
+            let io = self.sources.lock().unwrap().get(&event.token()).cloned();
+            if let Some(io) = io {
+                io.set_readiness(ready);
+            }
+        }
+    }
+}
+
+impl ScheduledIo {
+    // 收到事件时调用, 设置就绪状态并递增计数, 然后唤醒等待的任务
+    fn set_readiness(&self, ready: usize) {
+        let _ = self.readiness.fetch_update(Ordering::AcqRel, Ordering::Acquire, |current| {
+            let tick = (current >> TICK_SHIFT).wrapping_add(1);
+            Some((tick << TICK_SHIFT) | (current & (READABLE | WRITABLE)) | ready)
+        });
+
+        let (reader, writer) = {
+            let mut waiters = self.waiters.lock().unwrap();
+            let reader = if ready & READABLE != 0 { waiters.reader.take() } else { None };
+            let writer = if ready & WRITABLE != 0 { waiters.writer.take() } else { None };
+            (reader, writer)
+        };
+        // 唤醒任务时不持有锁
+        reader.into_iter().chain(writer).for_each(Waker::wake);
+    }
+
+    // 先保存 waker 再检查就绪状态, 这样检查之后收到的事件一定能唤醒任务
+    fn poll_ready(&self, cx: &mut Context<'_>, direction: Direction) -> Poll<usize> {
+        let mut waiters = self.waiters.lock().unwrap();
+        let current = self.readiness.load(Ordering::Acquire);
+        if current & direction.mask() != 0 {
+            return Poll::Ready(current >> TICK_SHIFT);
+        }
+
+        let slot = match direction {
+            Direction::Read => &mut waiters.reader,
+            Direction::Write => &mut waiters.writer,
+        };
+        match slot {
+            Some(waker) if waker.will_wake(cx.waker()) => {}
+            _ => *slot = Some(cx.waker().clone()),
+        }
+        Poll::Pending
+    }
+
+    // 读写返回 WouldBlock 时清除就绪状态. 如果计数已经变了, 说明期间又收到了新的事件, 保留就绪状态
+    fn clear_readiness(&self, tick: usize, direction: Direction) {
+        let _ = self.readiness.fetch_update(Ordering::AcqRel, Ordering::Acquire, |current| {
+            if current >> TICK_SHIFT == tick {
+                Some(current & !direction.mask())
+            } else {
+                None
+            }
+        });
+    }
+}
+
+/// 注册到 reactor 中的一个 socket
+///
+/// drop 时从 reactor 中删除. socket 本身的 fd 被关闭时会自动从 epoll 中移除.
+pub struct Registration {
+    reactor: Arc<Reactor>,
+    token: Token,
+    io: Arc<ScheduledIo>,
+}
+
+impl Registration {
+    /// 把 socket 以边缘触发的方式注册到当前线程的 reactor 中, 同时关注读写事件
+    ///
+    /// # Panics
+    ///
+    /// 不在 mini-tokio 的 worker 线程上调用时 panic
+    pub fn new(source: &mut impl Source) -> io::Result<Registration> {
+        let reactor = current();
+        let token = Token(reactor.next_token.fetch_add(1, Ordering::Relaxed));
+        let io = Arc::new(ScheduledIo {
+            readiness: AtomicUsize::new(0),
+            waiters: Mutex::new(Waiters::default()),
+        });
+
+        reactor.sources.lock().unwrap().insert(token, io.clone());
+        if let Err(e) = reactor.registry.register(source, token, Interest::READABLE | Interest::WRITABLE) {
+            reactor.sources.lock().unwrap().remove(&token);
+            return Err(e);
+        }
+
+        Ok(Registration { reactor, token, io })
+    }
+
+    /// 等待 socket 可读或者可写. 没有就绪时保存 waker, 收到事件后唤醒任务
+    pub fn poll_ready(&self, cx: &mut Context<'_>, direction: Direction) -> Poll<()> {
+        ready!(coop::poll_proceed(cx));
+        self.io.poll_ready(cx, direction).map(|_| ())
+    }
+
+    /// 在 socket 就绪后执行非阻塞的读写操作 `f`. `f` 返回 `WouldBlock` 时清除就绪状态, 等待下一次事件
+    pub fn poll_io<R>(
+        &self,
+        cx: &mut Context<'_>,
+        direction: Direction,
+        mut f: impl FnMut() -> io::Result<R>,
+    ) -> Poll<io::Result<R>> {
+        ready!(coop::poll_proceed(cx));
+
+        loop {
+            let tick = match self.io.poll_ready(cx, direction) {
+                Poll::Ready(tick) => tick,
+                Poll::Pending => return Poll::Pending,
+            };
+
+            match f() {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => self.io.clear_readiness(tick, direction),
+                result => return Poll::Ready(result),
+            }
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.reactor.sources.lock().unwrap().remove(&self.token);
+    }
+}
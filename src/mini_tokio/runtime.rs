@@ -0,0 +1,659 @@
+//! 多线程的执行器 `MiniTokio`.
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::Duration;
+
+use crossbeam::deque;
+use futures::task::{self, ArcWake};
+use futures::FutureExt;
+
+use super::blocking::BlockingPool;
+use super::coop;
+use super::join::{Completer, JoinError, JoinHandle, JoinState};
+use super::reactor::{self, Reactor};
+use super::scheduler::{Idle, Local, Queue, Scheduler, EVENT_INTERVAL, LOCAL};
+use super::sim::{Pick, Woken};
+use super::task::Task;
+use super::time::{self, Clock};
+
+// 用于跟踪当前的mini-tokio实例,来使 spawn 函数能调度产生的实例.
+thread_local! {
+    static CURRENT: RefCell<Option<Arc<Shared>>> = const { RefCell::new(None) };
+}
+
+/// 此spawn函数功能与tokio::spawn()一样. 当进行到mini-tokio执行器(executor)中时,
+/// 'CURRENT' 本地线程(thread-local) 被设置指向执行器 channel的 Send 方. 然后，产生task需要为创建的"Task"套上
+/// 一个"future" 并将其推到调度队列里面.
+///
+/// 返回的 `JoinHandle` 可以用来等待任务的输出, 或者取消任务. drop 掉 `JoinHandle` 不会影响任务的运行.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where F: Future + Send + 'static,
+      F::Output: Send + 'static,
+{
+    CURRENT.with(|cell| {
+        let borrow = cell.borrow();
+        let shared = borrow.as_ref().expect("must be called from the context of a mini-tokio runtime");
+        shared.spawn(future)
+    })
+}
+
+/// 在专门的线程池中执行一个阻塞的函数, 例如文件 I/O, 计算密集的工作或者同步的数据库客户端,
+/// 这样它不会占用执行异步任务的 worker. 与 tokio::task::spawn_blocking() 一样.
+///
+/// 线程池按需创建线程, 线程数的上限与空闲线程退出的时间可以通过 `Builder` 配置. 函数执行完后通过 `JoinHandle`
+/// 唤醒等待它的任务. 函数中的 panic 同样通过 `JoinHandle` 返回.
+pub fn spawn_blocking<F, R>(f: F) -> JoinHandle<R>
+where F: FnOnce() -> R + Send + 'static,
+      R: Send + 'static,
+{
+    CURRENT.with(|cell| {
+        let borrow = cell.borrow();
+        let shared = borrow.as_ref().expect("must be called from the context of a mini-tokio runtime");
+        shared.spawn_blocking(f)
+    })
+}
+
+// 调用当前执行器的 panic 钩子, 由 LocalSet 中 panic 的任务使用, 不在执行器中时什么都不做
+pub(super) fn on_task_panic(payload: &(dyn Any + Send)) {
+    // 先取出钩子再调用, 钩子中可以调用 spawn
+    let hook = CURRENT.with(|cell| cell.borrow().as_ref().and_then(|shared| shared.panic_hook.clone()));
+    if let Some(hook) = hook {
+        hook(payload);
+    }
+}
+
+/// spawn 与执行器共享的状态
+pub(super) struct Shared {
+    // 所有还没有完成的任务, 按 Arc 的地址索引. 执行器用它来判断是否还有任务在运行, 关闭时用它找到所有需要 drop 的 future
+    tasks: Mutex<HashMap<usize, Arc<Task>>>,
+    // 等待执行的任务
+    queue: Queue,
+    // 所有任务都已完成或者调用了 `Shutdown::shutdown`, worker 应当退出
+    stopping: AtomicBool,
+    // `block_on` 运行期间为 true, 这时所有任务都完成了 worker 也不退出, 而是等 `block_on` 的 future 完成
+    keep_alive: AtomicBool,
+    // 驱动 socket 的 I/O 事件
+    reactor: Arc<Reactor>,
+    // 空闲的 worker 在这里等待任务或者 I/O 事件
+    idle: Idle,
+    // 任务 panic 时调用
+    panic_hook: Option<Arc<PanicHook>>,
+    // 执行 spawn_blocking 产生的阻塞任务
+    blocking: BlockingPool,
+    // 模拟模式的虚拟时钟, 不是模拟模式时为 None
+    clock: Option<Arc<Clock>>,
+}
+
+/// 任务 panic 时调用的钩子, 参数是 panic 的参数
+type PanicHook = dyn Fn(&(dyn Any + Send)) + Send + Sync;
+
+impl Shared {
+    // 初始化一个新的包含了指定future的task，登记后推送到调度队列中.
+    //
+    // future 被包装为一个输出为 `bool` 的 future, 它捕获 future 中的 panic, 并把结果交给 JoinHandle.
+    fn spawn<F>(self: &Arc<Self>, future: F) -> JoinHandle<F::Output>
+    where F: Future + Send + 'static,
+          F::Output: Send + 'static,
+    {
+        let state = Arc::new(Mutex::new(JoinState { output: None, waker: None }));
+        let mut completer = Completer { state: state.clone(), done: false };
+
+        let panic_hook = self.panic_hook.clone();
+        let task = Task::new(
+            async move {
+                match AssertUnwindSafe(future).catch_unwind().await {
+                    Ok(output) => {
+                        completer.complete(Ok(output));
+                        false
+                    }
+                    Err(payload) => {
+                        // 先交给钩子, 再通过 JoinHandle 返回. 没有人等待 JoinHandle 时钩子是唯一能看到 panic 的地方
+                        if let Some(hook) = &panic_hook {
+                            hook(&*payload);
+                        }
+                        completer.complete(Err(JoinError::Panic(payload)));
+                        true
+                    }
+                }
+            },
+            self.clone(),
+        );
+        self.tasks.lock().unwrap().insert(task.key(), task.clone());
+        self.schedule(task.clone());
+
+        JoinHandle { state, task: Some(task) }
+    }
+
+    // 把任务的 future 在析构时产生的 panic 交给钩子. 钩子本身的 panic 也被捕获, 它们不能让执行器退出
+    pub(super) fn report_panic(&self, payload: &(dyn Any + Send)) {
+        if let Some(hook) = &self.panic_hook {
+            let _ = panic::catch_unwind(AssertUnwindSafe(|| hook(payload)));
+        }
+    }
+
+    // 把函数交给阻塞线程池. 函数执行完后 completer 唤醒 JoinHandle 保存的 waker, 也就是等待它的任务的 waker,
+    // 任务通过 ArcWake 重新被调度. 线程池关闭时还没有执行的函数被 drop, JoinHandle 得到取消的结果
+    fn spawn_blocking<F, R>(&self, f: F) -> JoinHandle<R>
+    where F: FnOnce() -> R + Send + 'static,
+          R: Send + 'static,
+    {
+        let state = Arc::new(Mutex::new(JoinState { output: None, waker: None }));
+        let mut completer = Completer { state: state.clone(), done: false };
+
+        let panic_hook = self.panic_hook.clone();
+        let job = move || match panic::catch_unwind(AssertUnwindSafe(f)) {
+            Ok(output) => completer.complete(Ok(output)),
+            Err(payload) => {
+                if let Some(hook) = &panic_hook {
+                    hook(&*payload);
+                }
+                completer.complete(Err(JoinError::Panic(payload)));
+            }
+        };
+        // 模拟模式下直接在当前线程上执行, 否则线程池中的线程会打乱确定的执行顺序
+        if self.clock.is_some() {
+            job();
+        } else {
+            self.blocking.spawn(Box::new(job));
+        }
+
+        JoinHandle { state, task: None }
+    }
+
+    // 把任务放入调度队列, 有 worker 在睡眠时唤醒一个
+    pub(super) fn schedule(&self, task: Arc<Task>) {
+        self.queue.push(task, self);
+        self.idle.notify_one();
+    }
+
+    // 把在 poll 的过程中被唤醒的任务放回调度队列. 与 schedule 不同, 任务不放入 LIFO 槽, 否则它会马上再次被执行,
+    // 而是排在所有已经在等待的任务后面
+    pub(super) fn schedule_yielded(&self, task: Arc<Task>) {
+        self.queue.push_yielded(task);
+        self.idle.notify_one();
+    }
+
+    // 任务完成后从登记表中删除, 返回是否所有的任务都已经完成
+    fn remove(&self, task: &Arc<Task>) -> bool {
+        let mut tasks = self.tasks.lock().unwrap();
+        tasks.remove(&task.key());
+        tasks.is_empty()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.tasks.lock().unwrap().is_empty()
+    }
+
+    // 通知所有的 worker 退出
+    fn stop(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        self.idle.notify_all();
+    }
+
+    fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+}
+
+/// 关闭执行器的句柄, 可以在任务中或者其它线程中调用
+#[derive(Clone)]
+pub struct Shutdown {
+    shared: Arc<Shared>,
+}
+
+impl Shutdown {
+    /// 通知执行器停止运行, `run` 会在处理完当前的任务后返回, 还没有完成的任务被 drop
+    pub fn shutdown(&self) {
+        self.shared.stop();
+    }
+}
+
+/// 一个非常基础的futures 执行器(executor). 当任务(task)被唤醒时,它们被放入调度队列中排队.
+/// 执行器的 worker 从队列中取出任务并执行.
+///
+/// 当一个任务被执行时，waker 中保存了任务本身, 唤醒时把任务重新放回队列.
+pub struct MiniTokio {
+    shared: Arc<Shared>,
+    // 执行任务的线程数
+    worker_threads: usize,
+}
+
+/// 配置并创建 `MiniTokio`
+pub struct Builder {
+    worker_threads: usize,
+    scheduler: Scheduler,
+    panic_hook: Option<Arc<PanicHook>>,
+    max_blocking_threads: usize,
+    thread_keep_alive: Duration,
+    simulation: Option<u64>,
+}
+
+impl Builder {
+    fn new() -> Builder {
+        Builder {
+            worker_threads: 1,
+            scheduler: Scheduler::WorkStealing,
+            panic_hook: None,
+            max_blocking_threads: 512,
+            thread_keep_alive: Duration::from_secs(10),
+            simulation: None,
+        }
+    }
+
+    /// 设置执行任务的线程数, 默认为 1, 即所有任务都在调用 `run` 或者 `block_on` 的线程上执行
+    ///
+    /// # Panics
+    ///
+    /// 如果 `n` 为 0 则 panic
+    pub fn worker_threads(&mut self, n: usize) -> &mut Builder {
+        assert!(n > 0, "worker_threads must be greater than 0");
+        self.worker_threads = n;
+        self
+    }
+
+    /// 设置调度器, 默认为 `Scheduler::WorkStealing`
+    pub fn scheduler(&mut self, scheduler: Scheduler) -> &mut Builder {
+        self.scheduler = scheduler;
+        self
+    }
+
+    /// 设置任务 panic 时调用的钩子, 它在执行任务的 worker 线程上被调用, 参数是 panic 的参数
+    ///
+    /// 不管有没有设置钩子, panic 的任务都会被标记为失败, panic 的参数通过 `JoinHandle` 返回, 执行器继续运行其它的任务.
+    pub fn on_task_panic<F>(&mut self, f: F) -> &mut Builder
+    where F: Fn(&(dyn Any + Send)) + Send + Sync + 'static,
+    {
+        self.panic_hook = Some(Arc::new(f));
+        self
+    }
+
+    /// 设置阻塞线程池的最大线程数, 默认为 512. 所有线程都在忙时, 新的 `spawn_blocking` 任务排队等待
+    ///
+    /// # Panics
+    ///
+    /// 如果 `n` 为 0 则 panic
+    pub fn max_blocking_threads(&mut self, n: usize) -> &mut Builder {
+        assert!(n > 0, "max_blocking_threads must be greater than 0");
+        self.max_blocking_threads = n;
+        self
+    }
+
+    /// 设置阻塞线程池中的线程空闲多久后退出, 默认为 10 秒
+    pub fn thread_keep_alive(&mut self, duration: Duration) -> &mut Builder {
+        self.thread_keep_alive = duration;
+        self
+    }
+
+    /// 使用模拟模式, 调度顺序与时间完全由 `seed` 决定, 见 `sim` 模块
+    ///
+    /// 所有任务都在调用 `run` 或者 `block_on` 的线程上执行, `worker_threads` 与 `scheduler` 被忽略.
+    /// `spawn_blocking` 的函数直接在当前线程上执行. socket 由真实的 I/O 事件驱动, 不能在模拟模式下使用.
+    pub fn simulation(&mut self, seed: u64) -> &mut Builder {
+        self.simulation = Some(seed);
+        self
+    }
+
+    /// 创建执行器, 创建 epoll 实例失败时返回错误
+    pub fn build(&mut self) -> io::Result<MiniTokio> {
+        let reactor = Arc::new(Reactor::new()?);
+
+        let shared = Arc::new(Shared {
+            tasks: Mutex::new(HashMap::new()),
+            queue: match self.simulation {
+                Some(seed) => Queue::simulation(seed),
+                None => Queue::new(self.scheduler, self.worker_threads),
+            },
+            stopping: AtomicBool::new(false),
+            keep_alive: AtomicBool::new(false),
+            reactor: reactor.clone(),
+            idle: Idle::new(reactor),
+            panic_hook: self.panic_hook.clone(),
+            blocking: BlockingPool::new(self.max_blocking_threads, self.thread_keep_alive),
+            clock: self.simulation.map(|_| Arc::new(Clock::new())),
+        });
+        Ok(MiniTokio{shared, worker_threads: self.worker_threads})
+    }
+}
+
+impl MiniTokio {
+    /// 返回一个 `Builder` 来配置执行器
+    pub fn builder() -> Builder {
+        Builder::new()
+    }
+
+    /// 返回一个关闭执行器的句柄
+    pub fn shutdown_handle(&self) -> Shutdown {
+        Shutdown { shared: self.shared.clone() }
+    }
+
+    /// 在mini-tokio实例上产生一个future
+    ///
+    /// 给future 包装task 并将其推送到调度队列中去,当run方法被调用时future将会执行
+    pub fn spawn<F>(&mut self, future: F) -> JoinHandle<F::Output>
+    where F: Future + Send + 'static,
+          F::Output: Send + 'static,
+    {
+        self.shared.spawn(future)
+    }
+
+    /// 运行执行器
+    ///
+    /// 这将启动执行器循环, 直到所有的任务都完成, 或者通过 `Shutdown` 句柄关闭了执行器.
+    /// 返回前所有还没有完成的任务的 future 都会被 drop, 它们的析构函数会在这里执行.
+    ///
+    /// 任务从调度队列中出来. 在队列中取出一个任务表明任务已经准备好被执行了.
+    /// 这发生在任务首次被创建和任务被唤醒时.
+    ///
+    /// 有多个 worker 时, 调用 `run` 的线程本身作为第一个 worker, 另外再启动 `worker_threads - 1` 个线程.
+    pub fn run(&self) {
+        if self.shared.clock.is_some() {
+            self.simulate(None::<std::future::Pending<()>>);
+            self.drop_tasks();
+            return;
+        }
+
+        if self.shared.is_empty() {
+            self.shared.stop();
+        }
+
+        let mut locals = self.shared.queue.take_locals();
+
+        thread::scope(|scope| {
+            let handles: Vec<_> = (1..self.worker_threads)
+                .map(|i| self.spawn_worker(scope, i, locals.get_mut(i).and_then(Option::take)))
+                .collect();
+
+            let mut locals = vec![self.work(0, locals.first_mut().and_then(Option::take), None)];
+            locals.extend(handles.into_iter().map(|handle| handle.join().unwrap()));
+            self.shared.queue.restore_locals(locals);
+        });
+
+        self.drop_tasks();
+        self.exit();
+    }
+
+    /// 在当前线程上运行 `future` 直到完成, 返回它的输出
+    ///
+    /// 与 `run` 一样, 当前线程作为第一个 worker 执行任务, 另外再启动 `worker_threads - 1` 个线程. future 被唤醒后
+    /// 当前线程在执行下一个任务之前 poll 它, 没有任务也没有被唤醒时与其它 worker 一样睡眠, 而不是像 mini-tokio-one
+    /// 那样不停地 poll. future 完成后 worker 退出, 还没有完成的任务保留下来, 在下一次调用 `run` 或者 `block_on`
+    /// 时继续执行, 或者在 `MiniTokio` 被 drop 时被 drop. 执行器在这之前被关闭时, 当前线程只 poll future, 不再执行任务.
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        // future panic 时也要通知 worker 退出, 否则 thread::scope 会一直等待它们
+        struct Stop<'a>(&'a Shared);
+
+        impl Drop for Stop<'_> {
+            fn drop(&mut self) {
+                self.0.keep_alive.store(false, Ordering::SeqCst);
+                self.0.stop();
+            }
+        }
+
+        if self.shared.clock.is_some() {
+            let output = self.simulate(Some(future)).unwrap();
+            self.shared.stopping.store(false, Ordering::SeqCst);
+            return output;
+        }
+
+        self.shared.keep_alive.store(true, Ordering::SeqCst);
+        let mut locals = self.shared.queue.take_locals();
+
+        let output = thread::scope(|scope| {
+            let handles: Vec<_> = (1..self.worker_threads)
+                .map(|i| self.spawn_worker(scope, i, locals.get_mut(i).and_then(Option::take)))
+                .collect();
+
+            let stop = Stop(&self.shared);
+            let mut future = std::pin::pin!(future);
+            let main_waker = Arc::new(MainWaker {
+                woken: AtomicBool::new(true),
+                thread: thread::current(),
+                shared: self.shared.clone(),
+            });
+            let waker = task::waker(main_waker.clone());
+            let mut cx = Context::from_waker(&waker);
+
+            let mut output = None;
+            let mut poll = || match coop::budget(|| future.as_mut().poll(&mut cx)) {
+                Poll::Ready(out) => {
+                    output = Some(out);
+                    true
+                }
+                Poll::Pending => false,
+            };
+            let mut main = Main { woken: &main_waker.woken, poll: &mut poll, done: false };
+            let mut locals = vec![self.work(0, locals.first_mut().and_then(Option::take), Some(&mut main))];
+
+            // 执行器被关闭了但 future 还没有完成, 只 poll future. 被 unpark 之后 park 才会返回, 如果在这之前已经调用了
+            // unpark 则立即返回. 偶尔也会无故返回, 多 poll 一次没有关系
+            self.enter();
+            while !main.poll_if_woken() {
+                thread::park();
+            }
+            drop(stop);
+
+            locals.extend(handles.into_iter().map(|handle| handle.join().unwrap()));
+            self.shared.queue.restore_locals(locals);
+            output.unwrap()
+        });
+
+        self.exit();
+        // 执行器可以再次运行
+        self.shared.stopping.store(false, Ordering::SeqCst);
+        output
+    }
+
+    // 模拟模式的执行循环. 每一步从就绪的任务与被唤醒了的 `future` 中按种子随机选择一个执行,
+    // 都不能执行时推进虚拟时钟. 没有 `future` 时在所有任务完成或者执行器被关闭后返回 None, 否则在 `future` 完成后返回.
+    // 时钟也不能推进时死锁了, 带着种子 panic
+    fn simulate<F: Future>(&self, future: Option<F>) -> Option<F::Output> {
+        // future 或者任务 panic 时也要清除当前线程上的执行器
+        struct Exit<'a>(&'a MiniTokio);
+
+        impl Drop for Exit<'_> {
+            fn drop(&mut self) {
+                self.0.exit();
+            }
+        }
+
+        let (Queue::Simulation(sim), Some(clock)) = (&self.shared.queue, &self.shared.clock) else {
+            unreachable!("simulate is only called in simulation mode");
+        };
+
+        let mut future = future.map(Box::pin);
+        let woken = Arc::new(Woken(AtomicBool::new(true)));
+        let waker = task::waker(woken.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        self.enter();
+        let _exit = Exit(self);
+        loop {
+            let main = future.is_some() && woken.0.load(Ordering::SeqCst);
+            let pick = sim.lock().unwrap().pick(main);
+            match pick {
+                Pick::Task(task) => {
+                    if task.clone().poll() {
+                        self.shared.remove(&task);
+                    }
+                    if future.is_none() && (self.shared.is_empty() || self.shared.is_stopping()) {
+                        return None;
+                    }
+                }
+                Pick::Main => {
+                    woken.0.store(false, Ordering::SeqCst);
+                    let future = future.as_mut().unwrap();
+                    if let Poll::Ready(output) = coop::budget(|| future.as_mut().poll(&mut cx)) {
+                        return Some(output);
+                    }
+                }
+                // 没有就绪的任务时推进时钟. 也没有计时器的话, 剩下的任务永远不会被唤醒了
+                Pick::Idle if clock.advance() => {}
+                Pick::Idle if future.is_none() && (self.shared.is_empty() || self.shared.is_stopping()) => return None,
+                Pick::Idle => {
+                    let seed = sim.lock().unwrap().seed();
+                    match future {
+                        Some(_) => panic!("simulation deadlocked with seed {}: the future passed to `block_on` can never complete", seed),
+                        None => panic!("simulation deadlocked with seed {}: the spawned tasks can never complete", seed),
+                    }
+                }
+            }
+        }
+    }
+
+    // 在 scope 中启动第 index 个 worker 线程
+    fn spawn_worker<'scope>(
+        &'scope self,
+        scope: &'scope thread::Scope<'scope, '_>,
+        index: usize,
+        queue: Option<deque::Worker<Arc<Task>>>,
+    ) -> thread::ScopedJoinHandle<'scope, Option<deque::Worker<Arc<Task>>>> {
+        thread::Builder::new()
+            .name(format!("mini-tokio-worker-{}", index))
+            .spawn_scoped(scope, move || self.work(index, queue, None))
+            .unwrap()
+    }
+
+    // 设置 CURRENT 线程局部变量来指向当前执行器, 这样任务中调用 spawn 时能找到执行器
+    // tokio 使用一个thread local变量来实现 `tokio::spawn`. 任务中创建的 socket 注册到执行器的 reactor 中
+    fn enter(&self) {
+        CURRENT.with(|cell| {
+            *cell.borrow_mut() = Some(self.shared.clone());
+        });
+        reactor::enter(Some(self.shared.reactor.clone()));
+        time::enter(self.shared.clock.clone());
+    }
+
+    fn exit(&self) {
+        CURRENT.with(|cell| {
+            *cell.borrow_mut() = None;
+        });
+        reactor::enter(None);
+        time::enter(None);
+    }
+
+    // worker 循环, 直到所有的任务都完成或者执行器被关闭. 返回工作窃取调度器的本地队列
+    //
+    // 调用 block_on 的线程传入 `main`, 在执行每个任务之前检查 block_on 的 future 是否被唤醒了, 被唤醒了就 poll 它,
+    // future 完成时立即返回
+    fn work(&self, index: usize, queue: Option<deque::Worker<Arc<Task>>>, mut main: Option<&mut Main<'_>>) -> Option<deque::Worker<Arc<Task>>> {
+        self.enter();
+        // 执行过的任务数, 用来定期检查 I/O 事件
+        let mut ticks = 0;
+        let woken = main.as_ref().map(|main| main.woken);
+        let is_woken = || woken.is_some_and(|woken| woken.load(Ordering::SeqCst));
+        let mut main_done = || main.as_mut().is_some_and(|main| main.poll_if_woken());
+
+        match &self.shared.queue {
+            Queue::Channel { receiver, .. } => {
+                // 没有任务时不能阻塞在 channel 上, 否则收不到 I/O 事件, 与工作窃取调度器一样在 Idle 中等待
+                while !self.shared.is_stopping() && !main_done() {
+                    match receiver.try_recv() {
+                        Ok(task) => self.run_task(task, &mut ticks),
+                        Err(_) => self.shared.idle.park(|| !receiver.is_empty() || self.shared.is_stopping() || is_woken()),
+                    }
+                }
+                None
+            }
+            Queue::WorkStealing(stealing) => {
+                LOCAL.with(|local| {
+                    *local.borrow_mut() = Some(Local::new(self.shared.clone(), index, queue.unwrap()));
+                });
+
+                while !self.shared.is_stopping() && !main_done() {
+                    // 取出任务后立即释放 LOCAL 的借用, poll 的过程中 spawn 与唤醒需要借用它
+                    let task = LOCAL.with(|local| stealing.next_task(local.borrow_mut().as_mut().unwrap()));
+                    match task {
+                        Some(task) => self.run_task(task, &mut ticks),
+                        None => self.shared.idle.park(|| stealing.has_work() || self.shared.is_stopping() || is_woken()),
+                    }
+                }
+
+                // LIFO 槽中剩余的任务放回本地队列, 在 drop_tasks 中统一清理
+                LOCAL.with(|local| local.borrow_mut().take()).map(Local::into_queue)
+            }
+            Queue::Simulation(_) => unreachable!("simulated runtimes run tasks on the calling thread"),
+        }
+    }
+
+    // 执行一个任务, 最后一个任务完成时通知所有 worker 退出
+    fn run_task(&self, task: Arc<Task>, ticks: &mut usize) {
+        if task.clone().poll() && self.shared.remove(&task) && !self.shared.keep_alive.load(Ordering::SeqCst) {
+            self.shared.stop();
+        }
+
+        // 每执行一定数量的任务检查一次 I/O 事件, 其它 worker 在 epoll_wait 上等待时直接跳过
+        *ticks = ticks.wrapping_add(1);
+        if ticks.is_multiple_of(EVENT_INTERVAL) {
+            self.shared.reactor.try_turn(Some(Duration::ZERO));
+        }
+    }
+
+    // drop 所有还没有完成的任务. future 的析构函数中可能又产生了新的任务, 所以循环直到没有任务为止
+    fn drop_tasks(&self) {
+        loop {
+            // 先把任务从登记表中取出来再 drop, 避免析构函数中调用 spawn 时死锁
+            let tasks = mem::take(&mut *self.shared.tasks.lock().unwrap());
+            if tasks.is_empty() {
+                break;
+            }
+            tasks.values().for_each(|task| task.shutdown());
+        }
+
+        // 清空调度队列中剩余的任务
+        self.shared.queue.clear();
+
+        // 之前的关闭通知已经没有意义了
+        self.shared.stopping.store(false, Ordering::SeqCst);
+    }
+}
+
+// block_on 的 future 在调用线程上与任务交替执行. `poll` 在 future 完成时返回 true
+struct Main<'a> {
+    woken: &'a AtomicBool,
+    poll: &'a mut dyn FnMut() -> bool,
+    done: bool,
+}
+
+impl Main<'_> {
+    // future 被唤醒了就 poll 它, 返回 future 是否已经完成
+    fn poll_if_woken(&mut self) -> bool {
+        if !self.done && self.woken.swap(false, Ordering::SeqCst) {
+            self.done = (self.poll)();
+        }
+        self.done
+    }
+}
+
+// block_on 的 future 的 waker. 调用线程可能在 Idle 中和其它 worker 一起睡眠, 不知道是哪一个, 所以唤醒所有睡眠的 worker.
+// 执行器被关闭后调用线程不再执行任务, 用 thread::park 睡眠, 所以还要 unpark 它
+struct MainWaker {
+    woken: AtomicBool,
+    thread: thread::Thread,
+    shared: Arc<Shared>,
+}
+
+impl ArcWake for MainWaker {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.woken.store(true, Ordering::SeqCst);
+        arc_self.shared.idle.notify_all();
+        arc_self.thread.unpark();
+    }
+}
+
+// 任务持有执行器的 Arc, 执行器被 drop 时要 drop 掉 block_on 之后剩下的任务, 否则它们与执行器互相引用, 永远不会被释放.
+// 阻塞线程池也在这时关闭, 正在执行的函数不会被打断, 但是不再等待它们
+impl Drop for MiniTokio {
+    fn drop(&mut self) {
+        self.shared.blocking.shutdown();
+        self.drop_tasks();
+    }
+}
@@ -0,0 +1,341 @@
+//! 调度器: 两种调度器的任务队列, 以及空闲 worker 的睡眠与唤醒.
+use std::cell::RefCell;
+use std::sync::atomic::{self, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::{iter, mem, ptr};
+
+// 使用一个channel队列来调度tasks, 之所以不用std中的channel是因为std中的channel不是 Sync的，无法在线程中共享
+use crossbeam::channel;
+// 工作窃取调度器使用的双端队列
+use crossbeam::deque;
+use rand::Rng;
+
+use super::reactor::Reactor;
+use super::runtime::Shared;
+use super::sim::Simulated;
+use super::task::Task;
+
+thread_local! {
+    // 工作窃取调度器的 worker 线程上的调度状态
+    pub(super) static LOCAL: RefCell<Option<Local>> = const { RefCell::new(None) };
+}
+
+/// 执行器使用的调度器
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scheduler {
+    /// 所有 worker 共享一个 crossbeam channel. 这是最初的实现, 保留下来用于与工作窃取调度器对比
+    Channel,
+    /// 每个 worker 有自己的本地队列, 空闲时从全局队列或者其它 worker 那里窃取任务, 与 tokio 的多线程调度器相同
+    WorkStealing,
+}
+
+// 两种调度器的任务队列
+pub(super) enum Queue {
+    Channel {
+        sender: channel::Sender<Arc<Task>>,
+        receiver: channel::Receiver<Arc<Task>>,
+    },
+    WorkStealing(Box<Stealing>),
+    // 模拟模式, 任务都在一个线程上执行, 锁只是为了让 Shared 是 Sync 的
+    Simulation(Mutex<Simulated>),
+}
+
+// 工作窃取调度器的队列
+//
+// 每个 worker 有一个 LIFO 槽和一个 FIFO 的本地队列. worker 线程上被唤醒 (或者产生) 的任务放入 LIFO 槽,
+// 槽中原来的任务被挤到本地队列的末尾, 这样刚被唤醒的任务会被马上执行, 它需要的数据很可能还在缓存中.
+// 其它线程 (比如计时器线程) 唤醒的任务放入全局的注入队列. worker 的本地队列为空时, 先从注入队列中取任务,
+// 再随机选择一个其它的 worker, 从它的本地队列中窃取一半的任务.
+pub(super) struct Stealing {
+    injector: deque::Injector<Arc<Task>>,
+    stealers: Vec<deque::Stealer<Arc<Task>>>,
+    // 每个 worker 的本地队列, 只在 `run` 运行期间被 worker 线程取走
+    locals: Mutex<Vec<deque::Worker<Arc<Task>>>>,
+}
+
+// worker 线程上的调度状态, 保存在 LOCAL 线程局部变量中
+pub(super) struct Local {
+    shared: Arc<Shared>,
+    index: usize,
+    queue: deque::Worker<Arc<Task>>,
+    lifo: Option<Arc<Task>>,
+    // 连续从 LIFO 槽中取出任务的次数
+    lifo_polls: usize,
+    // worker 取出任务的次数
+    tick: usize,
+    // 选择窃取对象用的 xorshift 随机数状态, 比每次调用 thread_rng 便宜
+    rng: u32,
+}
+
+impl Local {
+    pub(super) fn new(shared: Arc<Shared>, index: usize, queue: deque::Worker<Arc<Task>>) -> Local {
+        Local {
+            shared,
+            index,
+            queue,
+            lifo: None,
+            lifo_polls: 0,
+            tick: 0,
+            // xorshift 的状态不能为 0
+            rng: rand::thread_rng().gen::<u32>() | 1,
+        }
+    }
+
+    // worker 退出时把 LIFO 槽中剩余的任务放回本地队列, 返回本地队列
+    pub(super) fn into_queue(self) -> deque::Worker<Arc<Task>> {
+        if let Some(task) = self.lifo {
+            self.queue.push(task);
+        }
+        self.queue
+    }
+
+    fn next_rand(&mut self, n: usize) -> usize {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+        self.rng as usize % n
+    }
+}
+
+// LIFO 槽最多被连续使用的次数, 避免两个互相唤醒的任务饿死本地队列中的其它任务
+const MAX_LIFO_POLLS: usize = 3;
+// 每隔这么多次优先检查一次注入队列, 避免本地队列一直不空时注入队列中的任务饿死, 数值与 tokio 相同
+const GLOBAL_QUEUE_INTERVAL: usize = 61;
+// worker 每执行这么多个任务不阻塞地检查一次 I/O 事件, 避免 worker 一直忙碌时 socket 上的任务得不到唤醒
+pub(super) const EVENT_INTERVAL: usize = 61;
+
+// 没有任务时 worker 在这里睡眠
+//
+// 调度任务时如果有 worker 在睡眠就唤醒一个. `notified` 记录了还没有被消费的唤醒次数, 这样在 worker 检查完队列
+// 与开始等待之间发生的唤醒不会丢失.
+//
+// 睡眠的 worker 中有一个在 epoll_wait 上等待 (驱动 reactor), 其它的在条件变量上等待. 驱动 reactor 的 worker
+// 只能通过 `Reactor::unpark` 唤醒, 它被唤醒后把驱动 reactor 的工作交给一个在条件变量上等待的 worker.
+pub(super) struct Idle {
+    state: Mutex<IdleState>,
+    condvar: Condvar,
+    sleepers: AtomicUsize,
+    reactor: Arc<Reactor>,
+}
+
+struct IdleState {
+    // 还没有被消费的唤醒次数
+    notified: usize,
+    // 在条件变量上等待的 worker 数
+    waiting: usize,
+    // 是否有 worker 在 epoll_wait 上等待
+    driving: bool,
+}
+
+impl Queue {
+    pub(super) fn new(scheduler: Scheduler, worker_threads: usize) -> Queue {
+        match scheduler {
+            Scheduler::Channel => {
+                let (sender, receiver) = channel::unbounded();
+                Queue::Channel { sender, receiver }
+            }
+            Scheduler::WorkStealing => {
+                let locals: Vec<_> = (0..worker_threads).map(|_| deque::Worker::new_fifo()).collect();
+                Queue::WorkStealing(Box::new(Stealing {
+                    injector: deque::Injector::new(),
+                    stealers: locals.iter().map(deque::Worker::stealer).collect(),
+                    locals: Mutex::new(locals),
+                }))
+            }
+        }
+    }
+
+    pub(super) fn simulation(seed: u64) -> Queue {
+        Queue::Simulation(Mutex::new(Simulated::new(seed)))
+    }
+
+    // 把任务放入调度队列, `owner` 是任务所属的执行器
+    pub(super) fn push(&self, task: Arc<Task>, owner: &Shared) {
+        match self {
+            Queue::Channel { sender, .. } => {
+                // unbounded channel 的 send 永远不会阻塞. 任务通常是在 worker 线程上被唤醒的, 如果 channel 满了时
+                // send 阻塞, 同时唤醒的任务多于 channel 的容量就会让所有 worker 都阻塞在 send 上, 没有人再取出任务
+                let _ = sender.send(task);
+            }
+            Queue::WorkStealing(stealing) => {
+                // 在当前执行器的 worker 线程上时放入本地队列, 否则放入注入队列
+                let task = LOCAL.with(|local| match local.try_borrow_mut() {
+                    Ok(mut local) => match local.as_mut() {
+                        Some(local) if ptr::eq(&*local.shared, owner) => {
+                            if let Some(prev) = local.lifo.replace(task) {
+                                local.queue.push(prev);
+                            }
+                            None
+                        }
+                        _ => Some(task),
+                    },
+                    Err(_) => Some(task),
+                });
+                if let Some(task) = task {
+                    stealing.injector.push(task);
+                }
+            }
+            Queue::Simulation(sim) => sim.lock().unwrap().push(task),
+        }
+    }
+
+    // 把让出 worker 的任务放入调度队列. 工作窃取调度器把它放入注入队列, 而不是本地队列或者 LIFO 槽,
+    // 这样本地队列与注入队列中已经在等待的任务都会先于它执行. 放入本地队列的话, 注入队列中的任务要等
+    // GLOBAL_QUEUE_INTERVAL 次之后才有机会执行
+    pub(super) fn push_yielded(&self, task: Arc<Task>) {
+        match self {
+            Queue::Channel { sender, .. } => {
+                let _ = sender.send(task);
+            }
+            Queue::WorkStealing(stealing) => stealing.injector.push(task),
+            Queue::Simulation(sim) => sim.lock().unwrap().push(task),
+        }
+    }
+
+    // 工作窃取调度器的本地队列在运行期间交给各个 worker 线程
+    pub(super) fn take_locals(&self) -> Vec<Option<deque::Worker<Arc<Task>>>> {
+        match self {
+            Queue::WorkStealing(stealing) => mem::take(&mut *stealing.locals.lock().unwrap()).into_iter().map(Some).collect(),
+            Queue::Channel { .. } | Queue::Simulation(_) => vec![],
+        }
+    }
+
+    // worker 退出后把本地队列还给执行器, 其中剩余的任务在下一次运行时继续执行
+    pub(super) fn restore_locals(&self, locals: Vec<Option<deque::Worker<Arc<Task>>>>) {
+        if let Queue::WorkStealing(stealing) = self {
+            *stealing.locals.lock().unwrap() = locals.into_iter().flatten().collect();
+        }
+    }
+
+    // 清空队列中剩余的任务
+    pub(super) fn clear(&self) {
+        match self {
+            Queue::Channel { receiver, .. } => while receiver.try_recv().is_ok() {},
+            Queue::WorkStealing(stealing) => {
+                while steal(|| stealing.injector.steal()).is_some() {}
+                for queue in stealing.locals.lock().unwrap().iter() {
+                    while queue.pop().is_some() {}
+                }
+            }
+            Queue::Simulation(sim) => sim.lock().unwrap().clear(),
+        }
+    }
+}
+
+impl Stealing {
+    // 取出 worker 要执行的下一个任务, 没有任务时返回 None
+    pub(super) fn next_task(&self, local: &mut Local) -> Option<Arc<Task>> {
+        local.tick = local.tick.wrapping_add(1);
+
+        if local.tick.is_multiple_of(GLOBAL_QUEUE_INTERVAL) {
+            if let Some(task) = steal(|| self.injector.steal()) {
+                return Some(task);
+            }
+        }
+
+        if let Some(task) = local.lifo.take() {
+            if local.lifo_polls < MAX_LIFO_POLLS {
+                local.lifo_polls += 1;
+                return Some(task);
+            }
+            // LIFO 槽已经被连续使用了太多次, 把任务放到本地队列的末尾, 让其它任务先执行
+            local.queue.push(task);
+        }
+        local.lifo_polls = 0;
+
+        if let Some(task) = local.queue.pop() {
+            return Some(task);
+        }
+
+        // 本地队列为空, 先从注入队列中取一批任务, 再从随机的一个 worker 开始依次尝试窃取
+        let start = local.next_rand(self.stealers.len());
+        let others = (0..self.stealers.len())
+            .map(|i| (start + i) % self.stealers.len())
+            .filter(|&i| i != local.index);
+
+        steal(|| {
+            self.injector
+                .steal_batch_and_pop(&local.queue)
+                .or_else(|| others.clone().map(|i| self.stealers[i].steal_batch_and_pop(&local.queue)).collect())
+        })
+    }
+
+    // 是否还有可以被当前 worker 取出的任务
+    pub(super) fn has_work(&self) -> bool {
+        !self.injector.is_empty() || self.stealers.iter().any(|stealer| !stealer.is_empty())
+    }
+}
+
+// 重试窃取直到成功或者确定没有任务, `Steal::Retry` 表示与其它线程发生了竞争
+fn steal(mut f: impl FnMut() -> deque::Steal<Arc<Task>>) -> Option<Arc<Task>> {
+    iter::repeat_with(&mut f).find(|steal| !steal.is_retry()).and_then(deque::Steal::success)
+}
+
+impl Idle {
+    pub(super) fn new(reactor: Arc<Reactor>) -> Idle {
+        Idle {
+            state: Mutex::new(IdleState { notified: 0, waiting: 0, driving: false }),
+            condvar: Condvar::new(),
+            sleepers: AtomicUsize::new(0),
+            reactor,
+        }
+    }
+
+    // 任务入队后调用, 没有 worker 在睡眠时只需要读取一次原子变量
+    pub(super) fn notify_one(&self) {
+        // 与 park 中的 fence 配对: 要么 worker 看到了新入队的任务, 要么这里看到了睡眠的 worker
+        atomic::fence(Ordering::SeqCst);
+        if self.sleepers.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.notified += 1;
+        // 每个被唤醒的 worker 消费一次唤醒. 在条件变量上等待的 worker 都已经有了对应的唤醒时, 再唤醒驱动 reactor 的 worker
+        if state.notified <= state.waiting {
+            self.condvar.notify_one();
+        } else if state.driving {
+            self.reactor.unpark();
+        }
+    }
+
+    pub(super) fn notify_all(&self) {
+        let state = self.state.lock().unwrap();
+        self.condvar.notify_all();
+        if state.driving {
+            self.reactor.unpark();
+        }
+    }
+
+    // 睡眠直到被唤醒或者收到 I/O 事件, `ready` 返回 true 时不睡眠
+    pub(super) fn park(&self, ready: impl Fn() -> bool) {
+        let mut state = self.state.lock().unwrap();
+        self.sleepers.fetch_add(1, Ordering::SeqCst);
+        atomic::fence(Ordering::SeqCst);
+
+        while state.notified == 0 && !ready() {
+            if !state.driving {
+                // 没有 worker 在驱动 reactor, 由当前 worker 在 epoll_wait 上等待. 等待时不持有锁,
+                // 在这之后的唤醒会通过 unpark 让 epoll_wait 立即返回
+                state.driving = true;
+                drop(state);
+                self.reactor.turn(None);
+
+                state = self.state.lock().unwrap();
+                state.driving = false;
+                // 让一个在条件变量上等待的 worker 接着驱动 reactor
+                if state.waiting > 0 {
+                    self.condvar.notify_one();
+                }
+                break;
+            }
+
+            state.waiting += 1;
+            state = self.condvar.wait(state).unwrap();
+            state.waiting -= 1;
+        }
+        state.notified = state.notified.saturating_sub(1);
+
+        self.sleepers.fetch_sub(1, Ordering::SeqCst);
+    }
+}
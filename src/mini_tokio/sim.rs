@@ -0,0 +1,125 @@
+//! 模拟模式: 由种子决定调度顺序, 使用虚拟时钟的执行器, 用来可重复地测试并发的代码.
+//!
+//! 用 `Builder::simulation` 创建的执行器在调用 `run` 或者 `block_on` 的线程上执行所有任务, 每一步从就绪的任务中
+//! 按种子决定的伪随机顺序选择一个. 计时器使用虚拟时钟, 没有任务可以执行时时间直接跳到下一个计时器的到期时间,
+//! 所以测试中的 `delay` 不需要真的等待. 同一个种子每次运行的调度顺序与时间都完全一样, 换一个种子就是另一种交错.
+//! 没有就绪的任务也没有计时器, 但 `block_on` 的 future 或者 `run` 的任务还没有完成时, 它们永远不会再被唤醒了,
+//! `run` 与 `block_on` 都带着种子 panic, 而不是永远挂起.
+//!
+//! `check` 用大量的种子运行同一个测试, 失败时打印种子, 设置环境变量 `MINI_TOKIO_SEED` 后重新运行就能重放失败的那一次.
+use std::env;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use futures::task::ArcWake;
+
+use super::task::Task;
+use super::MiniTokio;
+
+/// 指定重放的种子的环境变量
+pub const SEED_VAR: &str = "MINI_TOKIO_SEED";
+
+/// 依次用种子 `0..iterations` 创建模拟模式的执行器并运行 `test`
+///
+/// 某个种子下 `test` panic 时打印这个种子后继续 panic. 设置了环境变量 `MINI_TOKIO_SEED` 时只运行这一个种子,
+/// 用来重放失败的测试.
+///
+/// # Panics
+///
+/// 如果 `MINI_TOKIO_SEED` 不是一个整数则 panic
+pub fn check<F>(iterations: u64, test: F)
+where F: Fn(&MiniTokio),
+{
+    match env::var(SEED_VAR) {
+        Ok(seed) => check_seeds(Some(seed.parse().expect("MINI_TOKIO_SEED must be an integer")), test),
+        Err(_) => check_seeds(0..iterations, test),
+    }
+}
+
+/// 与 `check` 相同, 但是只运行指定的种子, 不读取环境变量
+pub fn check_seeds<I, F>(seeds: I, test: F)
+where I: IntoIterator<Item = u64>,
+      F: Fn(&MiniTokio),
+{
+    for seed in seeds {
+        let mini_tokio = MiniTokio::builder().simulation(seed).build().unwrap();
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| test(&mini_tokio))) {
+            eprintln!("simulation failed with seed {}, replay it with {}={}", seed, SEED_VAR, seed);
+            panic::resume_unwind(payload);
+        }
+    }
+}
+
+// 模拟模式的调度队列: 就绪的任务与决定执行顺序的随机数生成器
+pub(super) struct Simulated {
+    ready: Vec<Arc<Task>>,
+    rng: Rng,
+    seed: u64,
+}
+
+// 模拟执行器每一步的选择
+pub(super) enum Pick {
+    Task(Arc<Task>),
+    // 执行 block_on 的 future
+    Main,
+    // 没有就绪的任务
+    Idle,
+}
+
+impl Simulated {
+    pub(super) fn new(seed: u64) -> Simulated {
+        Simulated { ready: Vec::new(), rng: Rng(seed), seed }
+    }
+
+    pub(super) fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub(super) fn push(&mut self, task: Arc<Task>) {
+        self.ready.push(task);
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.ready.clear();
+    }
+
+    // 从就绪的任务中随机选择一个, `main` 表示 block_on 的 future 是否也被唤醒了, 它与任务一样参与选择
+    pub(super) fn pick(&mut self, main: bool) -> Pick {
+        let n = self.ready.len() + main as usize;
+        if n == 0 {
+            return Pick::Idle;
+        }
+
+        match self.rng.below(n) {
+            i if i == self.ready.len() => Pick::Main,
+            i => Pick::Task(self.ready.swap_remove(i)),
+        }
+    }
+}
+
+// splitmix64, 不依赖 rand 的版本, 保证同一个种子在任何环境下产生同样的序列
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+// 模拟模式下 block_on 的 future 的 waker, 只记录 future 被唤醒了, 由执行循环决定什么时候 poll 它
+pub(super) struct Woken(pub(super) AtomicBool);
+
+impl ArcWake for Woken {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.0.store(true, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,50 @@
+//! 第一阶段的 mini-tokio, 忙等待地轮询所有任务.
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::Context;
+use futures::task;
+
+/// 第一版 mini-tokio: 所有任务放在一个队列中, 使用 `noop_waker` 不停地轮询, 直到所有任务都完成
+///
+/// 没有 waker 通知任务什么时候可以继续执行, 所以 CPU 会一直处于忙碌的状态.
+pub struct MiniTokio {
+    tasks: VecDeque<Task>,
+}
+
+type Task = Pin<Box<dyn Future<Output=()> + Send>>;
+
+
+impl MiniTokio {
+    // 初始化一个MiniTokio 对象
+    pub fn new() -> Self {
+        MiniTokio { tasks: VecDeque::new() }
+    }
+
+    // 在mini-tokio实例上产生一个future
+    pub fn spawn<F>(&mut self, future: F)
+        where
+            F: Future<Output=()> + Send + 'static,
+    {
+        self.tasks.push_back(Box::pin(future));
+    }
+
+    pub fn run(&mut self) {
+        // 创建一个新waker
+        let waker = task::noop_waker();
+        let mut context = Context::from_waker(&waker);
+
+        // 循环队列中拿任务task 并匹配，
+        while let Some(mut task) = self.tasks.pop_front() {
+            if task.as_mut().poll(&mut context).is_pending() {
+                self.tasks.push_back(task);
+            }
+        }
+    }
+}
+
+impl Default for MiniTokio {
+    fn default() -> Self {
+        MiniTokio::new()
+    }
+}
@@ -0,0 +1,84 @@
+//! 第二阶段的 mini-tokio, 使用 waker 与 channel 调度被唤醒的任务.
+use crossbeam::channel;
+use std::sync::{Arc, Mutex};
+use std::pin::Pin;
+use futures::{task, task::ArcWake, Future};
+use std::task::Context;
+
+struct Task {
+    future: Mutex<Pin<Box<dyn Future<Output=()> + Send>>>,
+    executor: channel::Sender<Arc<Task>>,
+}
+
+impl Task {
+    fn schedule(self: &Arc<Self>) {
+        let _ = self.executor.send(self.clone());
+    }
+
+    fn poll(self: Arc<Self>) {
+        // 从task实例上创建一个waker. 它使用 ArcWake
+        let waker = task::waker(self.clone());
+        let mut context = Context::from_waker(&waker);
+        // 没有其它线程试图锁住 future，所以可以try_lock()
+        let mut future = self.future.try_lock().unwrap();
+
+        // 轮询future
+        let _ = future.as_mut().poll(&mut context);
+    }
+
+    /// 使用指定的future产生一个新的任务
+    ///
+    /// 初始化一个新的task,它包含了future，完成后将task 推送到队列中, channel的另外一半receiver将接收到它们.
+    fn spawn<F>(future: F, sender: &channel::Sender<Arc<Task>>)
+        where F: Future<Output=()> + Send + 'static,
+    {
+        let task = Arc::new(Task {
+            future: Mutex::new(Box::pin(future)),
+            executor: sender.clone(),
+        });
+
+        let _ = sender.send(task);
+    }
+}
+
+impl ArcWake for Task {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.schedule();
+    }
+}
+
+/// 第二版 mini-tokio: 任务被唤醒时通过 channel 发送给执行器, 执行器只 poll 被唤醒的任务
+pub struct MiniTokio {
+    scheduled: channel::Receiver<Arc<Task>>,
+    sender: channel::Sender<Arc<Task>>,
+}
+
+impl MiniTokio {
+
+    // 此run方法，将会一直执行
+    pub fn run(&self) {
+        while let Ok(task) = self.scheduled.recv() {
+            task.poll();
+        }
+    }
+
+    pub fn new() -> Self {
+        // 使用 unbounded channel, 在执行器线程上唤醒任务时 send 不会阻塞. 如果使用 bounded channel,
+        // 同时唤醒的任务超过容量时执行器线程会阻塞在 send 上, 而它正是唯一从 channel 中取出任务的线程
+        let (sender, scheduled) = channel::unbounded();
+        MiniTokio { sender, scheduled }
+    }
+
+    /// MiniTokio 产生一个future
+    pub fn spawn<F>(&mut self, future: F)
+        where F: Future<Output=()> + Send + 'static
+    {
+        Task::spawn(future, &self.sender)
+    }
+}
+
+impl Default for MiniTokio {
+    fn default() -> Self {
+        MiniTokio::new()
+    }
+}
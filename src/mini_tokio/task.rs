@@ -0,0 +1,166 @@
+//! 任务与唤醒任务的 waker.
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+// 允许我们不使用 “不安全” 的代码来实现一个 `sta::task::waker` 功能的 工具
+use futures::task::{self, ArcWake};
+
+use super::coop;
+use super::join::Abort;
+use super::runtime::Shared;
+
+/// task 包含一个future和一旦future被唤醒后所必须要的数据
+pub(super) struct Task {
+    // future使用 Mutex 来包装可以使用Task具有Sync 特性.
+    // 仅有一个线程可以使用future.
+    // 真实tokio运行时，没有使用Mutex这种排它锁，而是使用了unsafe代码. box也被避免使用了.
+    // future 完成或者执行器关闭后被置为 None, 这样 future 中的资源会立即释放, 而不用等到所有的 waker 都被 drop.
+    // future 的输出表示任务是否 panic 了
+    future: Mutex<Option<Pin<Box<dyn Future<Output = bool> + Send>>>>,
+    // 当task被通知时，它被放入执行器的调度队列中去. 执行器通过取出通知任务来执行它们
+    executor: Arc<Shared>,
+    // 任务的调度状态, 见下面的常量
+    state: AtomicUsize,
+    // 被 `JoinHandle::abort` 取消, 执行器下一次调度它时直接 drop future
+    aborted: AtomicBool,
+}
+
+// 任务的状态. 只有 IDLE 状态的任务被唤醒时才放入调度队列, 这样同一个任务在队列中最多只有一份,
+// 被唤醒多少次都只会被 poll 一次.
+//
+// 等待被唤醒
+const IDLE: usize = 0;
+// 在调度队列中等待执行
+const SCHEDULED: usize = 1;
+// 正在被某个 worker poll
+const RUNNING: usize = 2;
+// 在 poll 的过程中被唤醒了, poll 结束后重新放入调度队列
+const NOTIFIED: usize = 3;
+// 已经完成, 被取消或者被执行器 drop 了, 不会再被调度
+const COMPLETE: usize = 4;
+// 任务 panic 了, 与 COMPLETE 一样不会再被调度
+const FAILED: usize = 5;
+
+impl Task {
+    // 创建一个任务, 新产生的任务马上被放入调度队列
+    pub(super) fn new<F>(future: F, executor: Arc<Shared>) -> Arc<Task>
+    where F: Future<Output = bool> + Send + 'static,
+    {
+        Arc::new(Task {
+            future: Mutex::new(Some(Box::pin(future))),
+            executor,
+            state: AtomicUsize::new(SCHEDULED),
+            aborted: AtomicBool::new(false),
+        })
+    }
+
+    // 任务在 `Shared::tasks` 中的键
+    pub(super) fn key(self: &Arc<Self>) -> usize {
+        Arc::as_ptr(self) as usize
+    }
+
+    // 执行器关闭时直接 drop 任务的 future, 之后任务不会再被调度.
+    // 析构函数中的 panic 不能从 `MiniTokio::drop` 传播出去, 捕获后交给钩子
+    pub(super) fn shutdown(&self) {
+        self.state.store(COMPLETE, Ordering::Release);
+        let future = self.future.lock().unwrap().take();
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| drop(future))) {
+            self.executor.report_panic(&*payload);
+        }
+    }
+
+    // 执行调度任务. 它创建了必须的 `task::Context`上下文，此Context,包含了一个waker与task.
+    // 使用waker对future进行poll. future 完成时返回 true
+    pub(super) fn poll(self: Arc<Self>) -> bool {
+        // 执行器关闭时任务可能已经被 drop 了, 忽略即可
+        if self.state.compare_exchange(SCHEDULED, RUNNING, Ordering::AcqRel, Ordering::Acquire).is_err() {
+            return false;
+        }
+
+        // 从task实例上创建一个waker, 它使用了 ArcWake
+        let waker = task::waker(self.clone());
+        // 使用waker来初始化task的上下文
+        let mut cx = Context::from_waker(&waker);
+
+        // 状态保证了同一时间只有一个 worker 在 poll 任务, 这里的锁不会有竞争
+        let mut slot = self.future.lock().unwrap();
+
+        // future 中的 panic 已经在 spawn 中捕获了, 但是 future 的析构函数与 panic 钩子也可能 panic.
+        // 这些 panic 在这里捕获, 任务被标记为失败, worker 继续执行其它的任务
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let future = slot.as_mut().expect("a scheduled task must have a future");
+
+            // 被取消的任务不再 poll, drop future 时 JoinHandle 会得到取消的结果.
+            // 轮询future, 完成后立即 drop
+            let state = if self.aborted.load(Ordering::Acquire) {
+                COMPLETE
+            } else {
+                // 每次 poll 任务时重新分配预算
+                match coop::budget(|| future.as_mut().poll(&mut cx)) {
+                    Poll::Ready(false) => COMPLETE,
+                    Poll::Ready(true) => FAILED,
+                    Poll::Pending => return None,
+                }
+            };
+            *slot = None;
+            Some(state)
+        }));
+
+        let state = match result {
+            Ok(state) => state,
+            // panic 的消息已经由标准库的 panic 钩子打印出来了
+            Err(_) => {
+                // future 还没有被 drop 的话在这里 drop, 析构函数可能再次 panic
+                let _ = panic::catch_unwind(AssertUnwindSafe(|| *slot = None));
+                Some(FAILED)
+            }
+        };
+        if let Some(state) = state {
+            self.state.store(state, Ordering::Release);
+            return true;
+        }
+        drop(slot);
+
+        // poll 的过程中被唤醒了, 重新放入调度队列. 不管被唤醒了多少次都只调度一次.
+        // 用完了预算或者调用了 yield_now 的任务也是这样, 它被放到队列的末尾, 让其它任务先执行
+        if let Err(state) = self.state.compare_exchange(RUNNING, IDLE, Ordering::AcqRel, Ordering::Acquire) {
+            debug_assert_eq!(state, NOTIFIED);
+            self.state.store(SCHEDULED, Ordering::Release);
+            self.executor.schedule_yielded(self.clone());
+        }
+        false
+    }
+
+    // 唤醒任务, 返回是否需要把任务放入调度队列
+    fn notify(&self) -> bool {
+        let result = self.state.fetch_update(Ordering::AcqRel, Ordering::Acquire, |state| match state {
+            IDLE => Some(SCHEDULED),
+            RUNNING => Some(NOTIFIED),
+            // 已经在队列中, 已经被通知过了, 或者已经完成了
+            _ => None,
+        });
+        result == Ok(IDLE)
+    }
+}
+
+impl Abort for Task {
+    fn abort(self: Arc<Self>) {
+        self.aborted.store(true, Ordering::Release);
+        ArcWake::wake_by_ref(&self);
+    }
+}
+
+// 在标准库中使用了一个低级别的API来定义waker,此API是unsafe的，为了不写unsafe代码，这里我们使用futures包提供的
+// ArcWake 来定义一个waker，它可以被 Task结构体来调度.
+impl ArcWake for Task {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        // 调度Task来执行.执行器从调度队列中取出Task并poll Task.
+        if arc_self.notify() {
+            arc_self.executor.schedule(arc_self.clone());
+        }
+    }
+}
@@ -0,0 +1,155 @@
+//! 任务局部变量, 由 `task_local!` 宏声明.
+//!
+//! 与线程局部变量不同, 任务可能在不同的 worker 线程上被 poll, 所以值保存在 `scope` 返回的 future 中,
+//! 每次 poll 时放入线程局部变量, poll 返回后再取出来. 这样请求范围内的值 (比如连接 id) 可以在任务的任何地方
+//! 访问, 不用在每个函数调用中传递. 与 tokio::task_local! 一样.
+use std::cell::RefCell;
+use std::fmt;
+use std::future::Future;
+use std::mem;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// 声明任务局部变量, 类型为 `LocalKey`
+///
+/// ```
+/// tokio_cn_doc::task_local! {
+///     pub static CONN_ID: u64;
+/// }
+///
+/// let id = CONN_ID.sync_scope(42, || CONN_ID.get());
+/// assert_eq!(id, 42);
+/// ```
+#[macro_export]
+macro_rules! task_local {
+    () => {};
+
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty; $($rest:tt)*) => {
+        $crate::__task_local_inner!($(#[$attr])* $vis $name, $t);
+        $crate::task_local!($($rest)*);
+    };
+
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty) => {
+        $crate::__task_local_inner!($(#[$attr])* $vis $name, $t);
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __task_local_inner {
+    ($(#[$attr:meta])* $vis:vis $name:ident, $t:ty) => {
+        $(#[$attr])*
+        $vis static $name: $crate::mini_tokio::task_local::LocalKey<$t> = {
+            std::thread_local! {
+                static __KEY: std::cell::RefCell<Option<$t>> = const { std::cell::RefCell::new(None) };
+            }
+
+            $crate::mini_tokio::task_local::LocalKey { inner: __KEY }
+        };
+    };
+}
+
+/// 任务局部变量的键
+pub struct LocalKey<T: 'static> {
+    #[doc(hidden)]
+    pub inner: std::thread::LocalKey<RefCell<Option<T>>>,
+}
+
+impl<T: 'static> LocalKey<T> {
+    /// 返回一个 future, 在它运行期间任务局部变量的值为 `value`
+    pub fn scope<F: Future>(&'static self, value: T, future: F) -> TaskLocalFuture<T, F> {
+        TaskLocalFuture { local: self, slot: Some(value), future: Box::pin(future) }
+    }
+
+    /// 在任务局部变量的值为 `value` 时同步地执行 `f`
+    pub fn sync_scope<F, R>(&'static self, value: T, f: F) -> R
+    where F: FnOnce() -> R,
+    {
+        self.scope_inner(&mut Some(value), f)
+    }
+
+    // 把 slot 中的值与线程局部变量中的值交换, 执行完 `f` 后 (包括 panic) 再换回来. 嵌套的 scope 会暂时替换外层的值
+    fn scope_inner<F, R>(&'static self, slot: &mut Option<T>, f: F) -> R
+    where F: FnOnce() -> R,
+    {
+        struct Guard<'a, T: 'static> {
+            local: &'static LocalKey<T>,
+            slot: &'a mut Option<T>,
+        }
+
+        impl<T: 'static> Drop for Guard<'_, T> {
+            fn drop(&mut self) {
+                self.local.inner.with(|cell| mem::swap(self.slot, &mut *cell.borrow_mut()));
+            }
+        }
+
+        self.inner.with(|cell| mem::swap(slot, &mut *cell.borrow_mut()));
+        let _guard = Guard { local: self, slot };
+        f()
+    }
+
+    /// 访问任务局部变量的值
+    ///
+    /// # Panics
+    ///
+    /// 如果不是在 `scope` 或者 `sync_scope` 中调用则 panic
+    pub fn with<F, R>(&'static self, f: F) -> R
+    where F: FnOnce(&T) -> R,
+    {
+        self.try_with(f).expect("cannot access a task-local storage value without setting it via `LocalKey::scope`")
+    }
+
+    /// 访问任务局部变量的值, 不是在 `scope` 或者 `sync_scope` 中调用时返回错误
+    pub fn try_with<F, R>(&'static self, f: F) -> Result<R, AccessError>
+    where F: FnOnce(&T) -> R,
+    {
+        self.inner.with(|cell| match cell.borrow().as_ref() {
+            Some(value) => Ok(f(value)),
+            None => Err(AccessError),
+        })
+    }
+
+    /// 返回任务局部变量的值的副本
+    ///
+    /// # Panics
+    ///
+    /// 如果不是在 `scope` 或者 `sync_scope` 中调用则 panic
+    pub fn get(&'static self) -> T
+    where T: Clone,
+    {
+        self.with(T::clone)
+    }
+}
+
+/// `LocalKey::scope` 返回的 future, 每次 poll 时设置任务局部变量的值
+pub struct TaskLocalFuture<T: 'static, F> {
+    local: &'static LocalKey<T>,
+    // 不在 poll 中时保存任务局部变量的值
+    slot: Option<T>,
+    // 放在 Box 中, 这样不用 unsafe 的 pin 投影
+    future: Pin<Box<F>>,
+}
+
+// 值从来不会被 pin 住, future 在 Box 中, 所以不管 T 与 F 是什么都可以移动
+impl<T: 'static, F> Unpin for TaskLocalFuture<T, F> {}
+
+impl<T: 'static, F: Future> Future for TaskLocalFuture<T, F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let TaskLocalFuture { local, slot, future } = &mut *self;
+        local.scope_inner(slot, || future.as_mut().poll(cx))
+    }
+}
+
+/// 不在 `scope` 中访问任务局部变量时返回的错误
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AccessError;
+
+impl fmt::Display for AccessError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        "task-local value not set".fmt(fmt)
+    }
+}
+
+impl std::error::Error for AccessError {}
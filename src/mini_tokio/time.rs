@@ -0,0 +1,448 @@
+//! 计时器: 所有计时器共享的分层时间轮 (hierarchical timing wheel), 由一个计时器线程驱动.
+//!
+//! 之前每个等待中的计时器都要占用一个线程, 一千个并发的 sleep 就需要一千个线程. 现在所有的计时器都保存在同一个
+//! 时间轮中, 只有一个计时器线程睡眠到最近的一个到期时间, 然后唤醒所有到期的计时器. 实现参考了 tokio 的时间轮.
+//!
+//! 模拟模式的执行器使用虚拟时钟 `Clock` 代替时间轮, 时间只在没有任务可以执行时跳到下一个计时器的到期时间.
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::mem;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex, Once, OnceLock};
+use std::task::{ready, Context, Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::coop;
+
+// 时间轮的层数, 每层 64 个槽. 第 0 层每个槽代表 1 毫秒, 第 1 层每个槽代表 64 毫秒, 依次类推
+const LEVELS: usize = 6;
+const SLOTS: usize = 64;
+const SLOT_BITS: usize = 6;
+// 时间轮能表示的最大时间范围 (毫秒), 约 2 年, 更远的计时器先放在最后一层, 到时再重新放置
+const MAX_DURATION: u64 = 1 << (SLOT_BITS * LEVELS);
+
+/// 在指定的时间点完成的 future
+///
+/// 第一次被 poll 时向时间轮注册, 之后的 poll 只更新 waker. drop 时从时间轮中注销, 被取消的计时器不会再唤醒任务.
+#[derive(Debug)]
+pub struct Sleep {
+    when: Instant,
+    // 在时间轮中的 id, 还没有注册时为 None
+    id: Option<u64>,
+    // 注册在模拟执行器的虚拟时钟中时, 从这个时钟中注销
+    clock: Option<Arc<Clock>>,
+}
+
+/// 返回一个在 `when` 时完成的 future
+pub fn sleep_until(when: Instant) -> Sleep {
+    Sleep { when, id: None, clock: None }
+}
+
+/// 返回当前时间. 在模拟模式的执行器中返回虚拟时钟的时间, 否则与 `Instant::now()` 相同
+pub fn now() -> Instant {
+    CLOCK.with(|cell| cell.borrow().as_ref().map_or_else(Instant::now, |clock| clock.now()))
+}
+
+/// 异步等待，其作用相当于 thread::sleep. 尝试在当前函数上暂停指定的时间
+///
+/// 最初的 mini-tokio 为每次调用 delay 都产生一个计时器线程, sleep 指定的duration后再通知调用者. 现在所有的计时器都由
+/// 共享的时间轮管理, 只有一个计时器线程, 上千个并发的 delay 也不会产生上千个线程.
+/// future 被 drop 时 (比如任务被取消) 计时器也会从时间轮中注销.
+pub async fn delay(dur: Duration) {
+    // delay 在这里是一种片面的future描述. 有时候，它被当作一种 "resource"(资源). 其它的资源包括,socket与channels.
+    // resource 可能不是按 async/await 来实现的，因为它们必须与一些操作系统细节合并. 因为这一原因，`Sleep` 是手动实现的 future
+    //
+    // 不过，最好将API公共为一个 async fn . 一个有用的方式是，手动定义私有future,然后从公共(pub)的`async fn`中使用它的API.
+    sleep_until(now() + dur).await;
+}
+
+/// 官方指南中手动实现的 `Delay` future, 到期时打印 "hello world" 并返回 "done"
+pub struct Delay {
+    // 由共享的时间轮驱动, 不再为每个 Delay 产生一个定时器线程
+    sleep: Sleep,
+}
+
+impl Delay {
+    pub fn new(when: Instant) -> Delay {
+        Delay { sleep: sleep_until(when) }
+    }
+}
+
+impl Future for Delay {
+    type Output = &'static str;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+
+        // 没到时间时 sleep 会把当前任务的 waker 注册到时间轮中, 到期后计时器线程唤醒它
+        if Pin::new(&mut self.sleep).poll(cx).is_ready() {
+            Poll::Ready("done")
+        }else {
+            Poll::Pending
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        ready!(coop::poll_proceed(cx));
+
+        let clock = CLOCK.with(|cell| cell.borrow().clone());
+        let now = clock.as_ref().map_or_else(Instant::now, |clock| clock.now());
+        if now >= self.when {
+            self.deregister();
+            return Poll::Ready(());
+        }
+
+        let id = match &clock {
+            Some(clock) => clock.register(self.id, self.when, cx.waker()),
+            None => driver().register(self.id, self.when, cx.waker()),
+        };
+        self.id = Some(id);
+        self.clock = clock;
+        Poll::Pending
+    }
+}
+
+impl Sleep {
+    // 从注册了计时器的时间轮或者虚拟时钟中注销
+    fn deregister(&mut self) {
+        if let Some(id) = self.id.take() {
+            match self.clock.take() {
+                Some(clock) => clock.cancel(self.when, id),
+                None => driver().cancel(id),
+            }
+        }
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.deregister();
+    }
+}
+
+thread_local! {
+    // 模拟模式的执行器运行时设置的虚拟时钟
+    static CLOCK: RefCell<Option<Arc<Clock>>> = const { RefCell::new(None) };
+}
+
+// 设置当前线程的虚拟时钟, 执行器运行时调用, None 表示使用真实的时间
+pub(super) fn enter(clock: Option<Arc<Clock>>) {
+    CLOCK.with(|cell| *cell.borrow_mut() = clock);
+}
+
+// 模拟模式的执行器使用的虚拟时钟
+//
+// 计时器按到期时间与注册顺序排序, 时间只在执行器调用 `advance` 时前进, 所以同一个种子每次运行的结果都一样,
+// 也不需要真的等待.
+#[derive(Debug)]
+pub(super) struct Clock {
+    state: Mutex<ClockState>,
+}
+
+#[derive(Debug)]
+struct ClockState {
+    now: Instant,
+    // 按 (到期时间, id) 排序的计时器
+    timers: BTreeMap<(Instant, u64), Waker>,
+    next_id: u64,
+}
+
+impl Clock {
+    // 虚拟时间从创建时的真实时间开始
+    pub(super) fn new() -> Clock {
+        Clock { state: Mutex::new(ClockState { now: Instant::now(), timers: BTreeMap::new(), next_id: 0 }) }
+    }
+
+    fn now(&self) -> Instant {
+        self.state.lock().unwrap().now
+    }
+
+    // 注册或者更新一个计时器, 返回它的 id
+    fn register(&self, id: Option<u64>, when: Instant, waker: &Waker) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        let id = id.unwrap_or_else(|| {
+            state.next_id += 1;
+            state.next_id
+        });
+        state.timers.insert((when, id), waker.clone());
+        id
+    }
+
+    fn cancel(&self, when: Instant, id: u64) {
+        self.state.lock().unwrap().timers.remove(&(when, id));
+    }
+
+    // 把时间推进到最早的计时器的到期时间, 唤醒所有在这时到期的计时器. 没有计时器时返回 false
+    pub(super) fn advance(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let now = match state.timers.keys().next() {
+            Some(&(when, _)) => when.max(state.now),
+            None => return false,
+        };
+        state.now = now;
+
+        let mut wakers = vec![];
+        while let Some(entry) = state.timers.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            wakers.push(entry.remove());
+        }
+        drop(state);
+
+        wakers.into_iter().for_each(Waker::wake);
+        true
+    }
+}
+
+// 计时器线程与 `Sleep` 共享的状态
+struct Driver {
+    wheel: Mutex<Wheel>,
+    // 计时器线程在它上面睡眠, 注册了更早的计时器时被唤醒
+    condvar: Condvar,
+    // 时间轮中的时间都是相对于 start 的毫秒数
+    start: Instant,
+}
+
+fn driver() -> &'static Driver {
+    static DRIVER: OnceLock<Driver> = OnceLock::new();
+    static START: Once = Once::new();
+
+    let driver = DRIVER.get_or_init(|| Driver {
+        wheel: Mutex::new(Wheel::new()),
+        condvar: Condvar::new(),
+        start: Instant::now(),
+    });
+    START.call_once(|| {
+        thread::Builder::new()
+            .name("timer".to_string())
+            .spawn(move || driver.run())
+            .unwrap();
+    });
+    driver
+}
+
+impl Driver {
+    // 注册或者更新一个计时器, 返回它的 id
+    fn register(&self, id: Option<u64>, when: Instant, waker: &Waker) -> u64 {
+        // 向上取整, 保证计时器不会提前触发
+        let when = self.ticks(when, true);
+        let mut wheel = self.wheel.lock().unwrap();
+
+        let earliest = wheel.next_deadline();
+        let id = wheel.insert(id, when, waker);
+
+        // 新的计时器比计时器线程等待的时间更早, 唤醒它重新计算睡眠时间
+        if earliest.is_none_or(|earliest| when < earliest) {
+            self.condvar.notify_one();
+        }
+        id
+    }
+
+    fn cancel(&self, id: u64) {
+        self.wheel.lock().unwrap().remove(id);
+    }
+
+    // 计时器线程的循环: 唤醒到期的计时器, 然后睡眠到下一个到期时间
+    fn run(&self) {
+        let mut wheel = self.wheel.lock().unwrap();
+        loop {
+            let wakers = wheel.poll(self.ticks(Instant::now(), false));
+            if !wakers.is_empty() {
+                // 唤醒任务时不持有锁, 被唤醒的任务可能马上就会注册新的计时器
+                drop(wheel);
+                wakers.into_iter().for_each(Waker::wake);
+                wheel = self.wheel.lock().unwrap();
+                continue;
+            }
+
+            wheel = match wheel.next_deadline() {
+                Some(deadline) => {
+                    let deadline = self.start + Duration::from_millis(deadline);
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    self.condvar.wait_timeout(wheel, timeout).unwrap().0
+                }
+                None => self.condvar.wait(wheel).unwrap(),
+            };
+        }
+    }
+
+    // 把时间点转换为相对于 start 的毫秒数
+    fn ticks(&self, when: Instant, round_up: bool) -> u64 {
+        let elapsed = when.saturating_duration_since(self.start);
+        let ms = elapsed.as_millis() as u64;
+        if round_up && elapsed > Duration::from_millis(ms) {
+            ms + 1
+        } else {
+            ms
+        }
+    }
+}
+
+// 分层时间轮
+//
+// 计时器按到期时间与当前时间的差放在不同的层中: 64 毫秒以内的在第 0 层, 4096 毫秒以内的在第 1 层, 依此类推.
+// 高层的一个槽到期时, 其中的计时器被重新放入更低的层, 最终在第 0 层中精确到毫秒地触发. 每层用一个 u64 记录
+// 哪些槽中有计时器, 这样可以直接跳到下一个有计时器的槽, 而不用逐毫秒地推进.
+struct Wheel {
+    // 时间轮已经推进到的时间
+    elapsed: u64,
+    levels: Vec<Level>,
+    // 所有注册了的计时器, 槽中只保存 id. 计时器被取消时只从这里删除, 槽中剩下的 id 在槽到期时被忽略
+    entries: HashMap<u64, Entry>,
+    next_id: u64,
+}
+
+struct Entry {
+    when: u64,
+    waker: Waker,
+}
+
+struct Level {
+    level: usize,
+    // 第 i 位表示第 i 个槽中有计时器
+    occupied: u64,
+    slots: Vec<Vec<u64>>,
+}
+
+// 下一个到期的槽
+struct Expiration {
+    level: usize,
+    slot: usize,
+    deadline: u64,
+}
+
+impl Wheel {
+    fn new() -> Wheel {
+        Wheel {
+            elapsed: 0,
+            levels: (0..LEVELS).map(Level::new).collect(),
+            entries: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    // 注册一个计时器, 已经注册的计时器只更新 waker
+    fn insert(&mut self, id: Option<u64>, when: u64, waker: &Waker) -> u64 {
+        if let Some(entry) = id.and_then(|id| self.entries.get_mut(&id)) {
+            if !entry.waker.will_wake(waker) {
+                entry.waker = waker.clone();
+            }
+            return id.unwrap();
+        }
+
+        // 计时器已经触发过了, 但是 Sleep 还在等待 (比如时钟的精度问题), 使用原来的 id 重新注册
+        let id = id.unwrap_or_else(|| {
+            self.next_id += 1;
+            self.next_id
+        });
+        self.entries.insert(id, Entry { when, waker: waker.clone() });
+        self.place(id, when);
+        id
+    }
+
+    fn remove(&mut self, id: u64) {
+        self.entries.remove(&id);
+    }
+
+    // 把计时器放入对应的层与槽中. 已经到期的计时器放在下一毫秒的槽中
+    fn place(&mut self, id: u64, when: u64) {
+        let when = when.clamp(self.elapsed + 1, self.elapsed + MAX_DURATION - 1);
+        let level = level_for(self.elapsed, when);
+        let slot = ((when >> (level * SLOT_BITS)) % SLOTS as u64) as usize;
+
+        let level = &mut self.levels[level];
+        level.slots[slot].push(id);
+        level.occupied |= 1 << slot;
+    }
+
+    // 推进到 now, 返回所有到期的计时器的 waker
+    fn poll(&mut self, now: u64) -> Vec<Waker> {
+        let mut wakers = vec![];
+
+        while let Some(expiration) = self.next_expiration() {
+            if expiration.deadline > now {
+                break;
+            }
+
+            let level = &mut self.levels[expiration.level];
+            let ids = mem::take(&mut level.slots[expiration.slot]);
+            level.occupied &= !(1 << expiration.slot);
+            self.elapsed = expiration.deadline;
+
+            for id in ids {
+                let when = match self.entries.get(&id) {
+                    Some(entry) => entry.when,
+                    // 已经被取消了
+                    None => continue,
+                };
+
+                if when <= self.elapsed {
+                    wakers.push(self.entries.remove(&id).unwrap().waker);
+                } else {
+                    // 高层的槽到期了, 其中的计时器放入更低的层
+                    self.place(id, when);
+                }
+            }
+        }
+
+        self.elapsed = self.elapsed.max(now);
+        wakers
+    }
+
+    // 最近一个有计时器的槽的到期时间, 计时器线程睡眠到这个时间
+    fn next_deadline(&self) -> Option<u64> {
+        self.next_expiration().map(|expiration| expiration.deadline)
+    }
+
+    fn next_expiration(&self) -> Option<Expiration> {
+        // 低层的槽总是比高层的槽先到期
+        self.levels.iter().find_map(|level| level.next_expiration(self.elapsed))
+    }
+}
+
+impl Level {
+    fn new(level: usize) -> Level {
+        Level {
+            level,
+            occupied: 0,
+            slots: (0..SLOTS).map(|_| vec![]).collect(),
+        }
+    }
+
+    fn next_expiration(&self, now: u64) -> Option<Expiration> {
+        if self.occupied == 0 {
+            return None;
+        }
+
+        // 从当前时间所在的槽的下一个槽开始, 找到下一个有计时器的槽. 当前的槽最后才被找到, 它只可能属于下一轮:
+        // 放入最后一层的很远的计时器可能与当前时间落在同一个槽中
+        let slot_range = 1u64 << (self.level * SLOT_BITS);
+        let level_range = slot_range << SLOT_BITS;
+        let start = ((now / slot_range + 1) % SLOTS as u64) as u32;
+        let slot = (self.occupied.rotate_right(start).trailing_zeros() + start) as usize % SLOTS;
+
+        let level_start = now & !(level_range - 1);
+        let mut deadline = level_start + slot as u64 * slot_range;
+        if deadline <= now {
+            // 槽在当前时间之前, 说明它属于这一层的下一轮. 只有最后一层的计时器会出现这种情况
+            deadline += level_range;
+        }
+
+        Some(Expiration { level: self.level, slot, deadline })
+    }
+}
+
+// 计算计时器应该放在哪一层: 到期时间与当前时间最高的不同位决定了层数
+fn level_for(elapsed: u64, when: u64) -> usize {
+    const SLOT_MASK: u64 = (1 << SLOT_BITS) - 1;
+
+    let masked = (elapsed ^ when | SLOT_MASK).min(MAX_DURATION - 1);
+    let significant = 63 - masked.leading_zeros() as usize;
+    significant / SLOT_BITS
+}
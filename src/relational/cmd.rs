@@ -0,0 +1,89 @@
+//! 从帧中解析出示例服务端支持的命令.
+use bytes::Bytes;
+use super::Result;
+use super::frame_enum::Frame;
+
+/// 示例服务端支持的命令
+#[derive(Debug)]
+pub enum Command {
+    Get(Get),
+    Set(Set),
+    // 不支持的命令, 保存命令名
+    Unknown(String),
+}
+
+/// `GET key`
+#[derive(Debug)]
+pub struct Get {
+    key: String,
+}
+
+/// `SET key value`
+#[derive(Debug)]
+pub struct Set {
+    key: String,
+    value: Bytes,
+}
+
+impl Command {
+    /// 从一个帧中解析出命令, 帧必须是由 bulk 字符串组成的数组
+    ///
+    /// 帧被消费掉, `SET` 的值直接取自帧中的 `Bytes`, 不会被复制.
+    pub fn from_frame(frame: Frame) -> Result<Command> {
+        let mut parts = match frame {
+            Frame::Array(parts) => parts.into_iter(),
+            frame => return Err(frame.to_error()),
+        };
+
+        let name = next_string(&mut parts)?.to_lowercase();
+
+        let command = match &name[..] {
+            "get" => Command::Get(Get {
+                key: next_string(&mut parts)?,
+            }),
+            "set" => Command::Set(Set {
+                key: next_string(&mut parts)?,
+                value: next_bytes(&mut parts)?,
+            }),
+            _ => return Ok(Command::Unknown(name)),
+        };
+
+        // 多余的参数
+        if parts.next().is_some() {
+            return Err(format!("ERR wrong number of arguments for '{}' command", name).into());
+        }
+
+        Ok(command)
+    }
+}
+
+impl Get {
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+}
+
+impl Set {
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn value(&self) -> &Bytes {
+        &self.value
+    }
+}
+
+// 取出下一个参数, 参数必须是 bulk 字符串
+fn next_bytes(parts: &mut impl Iterator<Item = Frame>) -> Result<Bytes> {
+    match parts.next() {
+        Some(Frame::Bulk(data)) => Ok(data),
+        Some(frame) => Err(frame.to_error()),
+        None => Err("ERR wrong number of arguments".into()),
+    }
+}
+
+// 取出下一个参数并转换为 String
+fn next_string(parts: &mut impl Iterator<Item = Frame>) -> Result<String> {
+    let data = next_bytes(parts)?;
+    Ok(String::from_utf8(data.to_vec())?)
+}
@@ -0,0 +1,401 @@
+//! 帧的编码与解码.
+//!
+//! `Decoder` 负责从读缓冲区中切出一个完整的帧, `Encoder` 负责把帧编码到写缓冲区中. 缓冲区的管理
+//! (从流中读取数据, 检测 EOF, 把写缓冲区写到流中) 由 [`Framed`](super::framed::Framed) 统一完成,
+//! 因此同一套逻辑可以用于 RESP, 按行分隔, 长度前缀等不同的协议.
+use bytes::{Buf, Bytes, BytesMut};
+use std::convert::TryInto;
+use std::io::{self, Cursor};
+use super::Result;
+use super::frame_enum::{self, Frame, Limits};
+use super::connection::Protocol;
+use super::write_buf::WriteBuf;
+
+/// 从字节缓冲区中解码帧
+pub trait Decoder {
+    /// 解码出的帧的类型
+    type Item;
+
+    /// 尝试从 `src` 中解码一个帧
+    ///
+    /// 如果 `src` 中还没有一个完整的帧则返回 `Ok(None)`, 并且不应该消费 `src` 中的数据, `Framed` 会继续
+    /// 从流中读取更多的数据后再次调用. 解码成功时, 帧对应的数据必须从 `src` 中移除.
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>>;
+
+    /// 流到达 EOF 时被调用, 用来处理缓冲区中剩余的数据
+    ///
+    /// 默认实现与 `decode` 相同. 如果解码后缓冲区中还有数据, `Framed` 会认为对等方在发送帧时关闭了链接.
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
+        self.decode(src)
+    }
+}
+
+/// 把帧编码为字节
+pub trait Encoder<Item> {
+    /// 把 `item` 编码后追加到 `dst` 中
+    ///
+    /// 较大的 `Bytes` 应当通过 `WriteBuf::put_bytes` 追加, 这样写出时不会被复制.
+    fn encode(&mut self, item: Item, dst: &mut WriteBuf) -> Result<()>;
+}
+
+/// Redis 协议 (RESP2/RESP3) 的编解码器
+///
+/// 解码时两种协议的帧都能被解析. 编码时按 `protocol` 决定是否把 RESP3 特有的帧降级为 RESP2 中对应的类型.
+#[derive(Debug)]
+pub struct RespCodec {
+    protocol: Protocol,
+    limits: Limits,
+}
+
+impl RespCodec {
+    /// 创建一个使用 RESP2 编码与默认 `Limits` 的编解码器
+    pub fn new() -> RespCodec {
+        RespCodec::with_limits(Limits::default())
+    }
+
+    /// 创建一个按指定 `limits` 解码的编解码器
+    pub fn with_limits(limits: Limits) -> RespCodec {
+        RespCodec {
+            protocol: Protocol::Resp2,
+            limits,
+        }
+    }
+
+    /// 返回解码时使用的大小限制
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// 返回编码时使用的协议版本
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    /// 切换编码时使用的协议版本
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.protocol = protocol;
+    }
+
+    // 编码一个帧, 数组中可以嵌套任意的帧, 所以这里需要递归
+    //
+    // 使用 RESP2 时, RESP3 特有的帧按 redis 的规则降级: map 展开为键值交替的数组, set 与 push 写为数组,
+    // double 与大数写为 bulk 字符串, 布尔值写为 1 或 0, 属性被丢弃只写入真正的回复.
+    fn encode_value(&self, frame: &Frame, dst: &mut WriteBuf) {
+        let resp3 = self.protocol == Protocol::Resp3;
+
+        match frame {
+            Frame::Simple(val) => put_line(dst, b'+', val.as_bytes()),
+            Frame::Error(val) => put_line(dst, b'-', val.as_bytes()),
+            Frame::Integer(val) => put_line(dst, b':', val.to_string().as_bytes()),
+            Frame::Null if resp3 => dst.put_slice(b"_\r\n"),
+            Frame::Null => dst.put_slice(b"$-1\r\n"),
+            Frame::Bulk(val) => {
+                put_line(dst, b'$', val.len().to_string().as_bytes());
+                dst.put_bytes(val.clone());
+                dst.put_slice(b"\r\n");
+            }
+            Frame::Array(val) => self.encode_elements(b'*', val, dst),
+            Frame::Set(val) => self.encode_elements(b'~', val, dst),
+            Frame::Push(val) => self.encode_elements(b'>', val, dst),
+            Frame::Map(pairs) => self.encode_pairs(b'%', pairs, dst),
+            Frame::Double(val) if resp3 => put_line(dst, b',', format_double(*val).as_bytes()),
+            Frame::Double(val) => put_blob(dst, b'$', &[format_double(*val).as_bytes()]),
+            Frame::Boolean(val) if resp3 => dst.put_slice(if *val { b"#t\r\n" } else { b"#f\r\n" }),
+            Frame::Boolean(val) => put_line(dst, b':', if *val { b"1" } else { b"0" }),
+            Frame::BigNumber(val) if resp3 => put_line(dst, b'(', val.as_bytes()),
+            Frame::BigNumber(val) => put_blob(dst, b'$', &[val.as_bytes()]),
+            Frame::Verbatim { format, data } if resp3 => put_blob(dst, b'=', &[format.as_bytes(), b":", data]),
+            Frame::Verbatim { data, .. } => put_blob(dst, b'$', &[data]),
+            Frame::Attribute { attrs, data } => {
+                if resp3 {
+                    self.encode_pairs(b'|', attrs, dst);
+                }
+                self.encode_value(data, dst);
+            }
+        }
+    }
+
+    // 编码数组, 集合或 push 帧, RESP2 下都编码为数组
+    fn encode_elements(&self, prefix: u8, val: &[Frame], dst: &mut WriteBuf) {
+        let prefix = match self.protocol {
+            Protocol::Resp2 => b'*',
+            Protocol::Resp3 => prefix,
+        };
+        put_line(dst, prefix, val.len().to_string().as_bytes());
+
+        for entry in val {
+            self.encode_value(entry, dst);
+        }
+    }
+
+    // 编码 map 或属性, RESP2 下 map 编码为键值交替的数组
+    fn encode_pairs(&self, prefix: u8, pairs: &[(Frame, Frame)], dst: &mut WriteBuf) {
+        match self.protocol {
+            Protocol::Resp2 => put_line(dst, b'*', (pairs.len() * 2).to_string().as_bytes()),
+            Protocol::Resp3 => put_line(dst, prefix, pairs.len().to_string().as_bytes()),
+        }
+
+        for (key, value) in pairs {
+            self.encode_value(key, dst);
+            self.encode_value(value, dst);
+        }
+    }
+}
+
+impl Default for RespCodec {
+    fn default() -> RespCodec {
+        RespCodec::new()
+    }
+}
+
+impl Decoder for RespCodec {
+    type Item = Frame;
+
+    /// 解析帧分为两步: 先用 `Frame::check` 在一个 `Cursor<&[u8]>` 上检查缓冲区中是否有一个完整的帧,
+    /// 再把帧对应的数据从缓冲区中切出来, 用 `Frame::parse_shared` 解析帧.
+    ///
+    /// 帧还不完整时, 如果缓冲区已经超过了 `max_buffer` 就返回错误, 不再继续等待更多的数据.
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame>> {
+        use frame_enum::Error::Incomplete;
+
+        loop {
+            // 创建一个 T:Buf 类型
+            let mut buf = Cursor::new(&src[..]);
+
+            // 检查是否为一个完整可用的帧
+            return match Frame::check_with_limits(&mut buf, &self.limits) {
+                Ok(_) => {
+                    // 得到帧的字节长度
+                    let len = buf.position() as usize;
+
+                    // 把整个帧从读缓冲区中切出来并冻结为 Bytes, 解析出的 bulk 数据都是它的切片,
+                    // 与读缓冲区共享同一块内存, 不会被复制
+                    let data = src.split_to(len).freeze();
+                    let frame = Frame::parse_shared(&data)?;
+
+                    // 与 redis 一样忽略空的 inline 命令, 比如在 telnet 中直接回车
+                    match frame {
+                        Frame::Array(ref args) if args.is_empty() && data[0] != b'*' => continue,
+                        frame => Ok(Some(frame)),
+                    }
+                }
+                Err(Incomplete) if src.len() > self.limits.max_buffer => {
+                    Err(frame_enum::Error::from("protocol error; too big request").into())
+                }
+                // 没有足够数据被缓存的情况
+                Err(Incomplete) => Ok(None),
+                Err(e) => Err(e.into()),
+            };
+        }
+    }
+}
+
+impl Encoder<&Frame> for RespCodec {
+    fn encode(&mut self, frame: &Frame, dst: &mut WriteBuf) -> Result<()> {
+        self.encode_value(frame, dst);
+        Ok(())
+    }
+}
+
+/// `LinesCodec` 默认的一行的最大字节数 (不包括换行符)
+pub const DEFAULT_MAX_LINE_LENGTH: usize = 64 * 1024;
+
+/// 按行分隔的文本编解码器, 每一行以 `\n` 结尾, 行尾的 `\r` 会被去掉
+///
+/// 一行超过 `max_length` 字节时返回 `InvalidData` 错误, 不会为一个没有换行符的流无限制地增长缓冲区.
+#[derive(Debug)]
+pub struct LinesCodec {
+    // 已经扫描过的位置, 避免每次都从缓冲区开头查找换行符
+    next_index: usize,
+    max_length: usize,
+}
+
+impl LinesCodec {
+    /// 创建一个一行最多 `DEFAULT_MAX_LINE_LENGTH` 字节的编解码器
+    pub fn new() -> LinesCodec {
+        LinesCodec::with_max_length(DEFAULT_MAX_LINE_LENGTH)
+    }
+
+    /// 创建一个一行最多 `max_length` 字节的编解码器
+    pub fn with_max_length(max_length: usize) -> LinesCodec {
+        LinesCodec { next_index: 0, max_length }
+    }
+
+    /// 返回一行的最大字节数
+    pub fn max_length(&self) -> usize {
+        self.max_length
+    }
+}
+
+impl Default for LinesCodec {
+    fn default() -> LinesCodec {
+        LinesCodec::new()
+    }
+}
+
+impl Decoder for LinesCodec {
+    type Item = String;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<String>> {
+        match src[self.next_index..].iter().position(|b| *b == b'\n') {
+            Some(offset) => {
+                let line = src.split_to(self.next_index + offset + 1);
+                self.next_index = 0;
+
+                let line = &line[..line.len() - 1];
+                let line = line.strip_suffix(b"\r").unwrap_or(line);
+                if line.len() > self.max_length {
+                    return Err(line_too_long());
+                }
+
+                Ok(Some(String::from_utf8(line.to_vec())?))
+            }
+            // 还没有收到换行符, 但已经超过了限制. 多留一个字节给行尾的 \r
+            None if src.len() > self.max_length + 1 => Err(line_too_long()),
+            None => {
+                self.next_index = src.len();
+                Ok(None)
+            }
+        }
+    }
+
+    /// 流结束时, 最后一行可以没有换行符
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<String>> {
+        match self.decode(src)? {
+            Some(line) => Ok(Some(line)),
+            None if src.is_empty() => Ok(None),
+            None => {
+                let line = src.split_to(src.len());
+                self.next_index = 0;
+                if line.len() > self.max_length {
+                    return Err(line_too_long());
+                }
+
+                Ok(Some(String::from_utf8(line.to_vec())?))
+            }
+        }
+    }
+}
+
+impl<T: AsRef<str>> Encoder<T> for LinesCodec {
+    fn encode(&mut self, line: T, dst: &mut WriteBuf) -> Result<()> {
+        let line = line.as_ref();
+
+        dst.reserve(line.len() + 1);
+        dst.put_slice(line.as_bytes());
+        dst.put_u8(b'\n');
+
+        Ok(())
+    }
+}
+
+/// `LengthDelimitedCodec` 默认的帧的最大字节数, 与 tokio-util 相同
+pub const DEFAULT_MAX_FRAME_LENGTH: usize = 8 * 1024 * 1024;
+
+/// 长度前缀的编解码器, 每个帧前面是一个 4 字节大端序的长度
+///
+/// 长度由对方发送, 所以解码时先检查它是否超过 `max_frame_length`, 超过时返回 `InvalidData` 错误,
+/// 否则一个 `\xff\xff\xff\xff` 的帧头就能让缓冲区预留 4 GB 的空间.
+#[derive(Debug)]
+pub struct LengthDelimitedCodec {
+    max_frame_length: usize,
+}
+
+impl LengthDelimitedCodec {
+    /// 创建一个帧最多 `DEFAULT_MAX_FRAME_LENGTH` 字节的编解码器
+    pub fn new() -> LengthDelimitedCodec {
+        LengthDelimitedCodec::with_max_frame_length(DEFAULT_MAX_FRAME_LENGTH)
+    }
+
+    /// 创建一个帧最多 `max_frame_length` 字节的编解码器
+    pub fn with_max_frame_length(max_frame_length: usize) -> LengthDelimitedCodec {
+        LengthDelimitedCodec { max_frame_length }
+    }
+
+    /// 返回帧的最大字节数
+    pub fn max_frame_length(&self) -> usize {
+        self.max_frame_length
+    }
+}
+
+impl Default for LengthDelimitedCodec {
+    fn default() -> LengthDelimitedCodec {
+        LengthDelimitedCodec::new()
+    }
+}
+
+impl Decoder for LengthDelimitedCodec {
+    type Item = Bytes;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Bytes>> {
+        if src.len() < 4 {
+            return Ok(None);
+        }
+
+        let len = u32::from_be_bytes(src[..4].try_into()?) as usize;
+        if len > self.max_frame_length {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "frame length limit exceeded").into());
+        }
+        if src.len() < 4 + len {
+            // 提前为剩余的数据预留空间
+            src.reserve(4 + len - src.len());
+            return Ok(None);
+        }
+
+        src.advance(4);
+        Ok(Some(src.split_to(len).freeze()))
+    }
+}
+
+impl Encoder<Bytes> for LengthDelimitedCodec {
+    fn encode(&mut self, data: Bytes, dst: &mut WriteBuf) -> Result<()> {
+        if data.len() > self.max_frame_length {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "frame length limit exceeded").into());
+        }
+        let len: u32 = data.len().try_into().map_err(|_| "frame too large")?;
+
+        dst.put_u32(len);
+        dst.put_bytes(data);
+
+        Ok(())
+    }
+}
+
+// 一行超过了 `max_length`
+fn line_too_long() -> super::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "line length limit exceeded").into()
+}
+
+// 写入一个以 \r\n 结尾的行
+fn put_line(dst: &mut WriteBuf, prefix: u8, line: &[u8]) {
+    dst.reserve(line.len() + 3);
+    dst.put_u8(prefix);
+    dst.put_slice(line);
+    dst.put_slice(b"\r\n");
+}
+
+// 写入一个带长度前缀的数据, 数据由 `parts` 拼接而成
+fn put_blob(dst: &mut WriteBuf, prefix: u8, parts: &[&[u8]]) {
+    let len: usize = parts.iter().map(|part| part.len()).sum();
+
+    put_line(dst, prefix, len.to_string().as_bytes());
+    dst.reserve(len + 2);
+    for part in parts {
+        dst.put_slice(part);
+    }
+    dst.put_slice(b"\r\n");
+}
+
+// RESP3 中的特殊浮点数写为 `inf`, `-inf` 与 `nan`
+fn format_double(val: f64) -> String {
+    if val.is_nan() {
+        "nan".to_string()
+    } else if val.is_infinite() {
+        if val > 0.0 { "inf" } else { "-inf" }.to_string()
+    } else {
+        val.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,334 @@
+// `RespCodec` 的往返测试: 任意生成的帧编码后再解码, 结果必须与原来的帧相同.
+use bytes::{Buf, Bytes, BytesMut};
+use proptest::prelude::*;
+use std::io::{self, Cursor};
+use std::ops::Range;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
+use super::{Decoder, Encoder, LengthDelimitedCodec, LinesCodec, RespCodec};
+use crate::relational::cmd::Command;
+use crate::relational::connection::Protocol;
+use crate::relational::frame_enum::Frame;
+use crate::relational::framed::Framed;
+use crate::relational::write_buf::WriteBuf;
+
+// 单行的帧 (simple, error) 中不能出现 \r 与 \n
+fn line() -> impl Strategy<Value = String> {
+    "[^\r\n]{0,16}"
+}
+
+fn bytes() -> impl Strategy<Value = Bytes> {
+    prop::collection::vec(any::<u8>(), 0..64).prop_map(Bytes::from)
+}
+
+fn leaf() -> impl Strategy<Value = Frame> {
+    prop_oneof![
+        line().prop_map(Frame::Simple),
+        line().prop_map(Frame::Error),
+        any::<i64>().prop_map(Frame::Integer),
+        bytes().prop_map(Frame::Bulk),
+        Just(Frame::Null),
+        // NaN 与自身不相等, 无法用 == 比较
+        any::<f64>().prop_filter("nan", |val| !val.is_nan()).prop_map(Frame::Double),
+        any::<bool>().prop_map(Frame::Boolean),
+        "-?[1-9][0-9]{0,40}".prop_map(Frame::BigNumber),
+        ("[a-z]{3}", bytes()).prop_map(|(format, data)| Frame::Verbatim { format, data }),
+    ]
+}
+
+fn frame() -> impl Strategy<Value = Frame> {
+    leaf().prop_recursive(4, 64, 8, |inner| {
+        let pairs = prop::collection::vec((inner.clone(), inner.clone()), 0..4);
+        prop_oneof![
+            prop::collection::vec(inner.clone(), 0..8).prop_map(Frame::Array),
+            prop::collection::vec(inner.clone(), 0..8).prop_map(Frame::Set),
+            prop::collection::vec(inner.clone(), 0..8).prop_map(Frame::Push),
+            pairs.clone().prop_map(Frame::Map),
+            (pairs, inner).prop_map(|(attrs, data)| Frame::Attribute { attrs, data: Box::new(data) }),
+        ]
+    })
+}
+
+// 用 RESP3 编码, 保留所有帧的类型
+fn encode(frame: &Frame) -> Vec<u8> {
+    let mut codec = RespCodec::new();
+    codec.set_protocol(Protocol::Resp3);
+
+    let mut dst = WriteBuf::new();
+    codec.encode(frame, &mut dst).unwrap();
+    dst.to_bytes().to_vec()
+}
+
+// 把数据按给定的位置切分为多段
+fn split_at(data: &[u8], mut cuts: Vec<usize>) -> Vec<Vec<u8>> {
+    cuts.iter_mut().for_each(|cut| *cut %= data.len() + 1);
+    cuts.sort_unstable();
+    cuts.push(data.len());
+
+    let mut start = 0;
+    cuts.into_iter()
+        .map(|end| {
+            let chunk = data[start..end].to_vec();
+            start = end;
+            chunk
+        })
+        .collect()
+}
+
+// 每次 poll_read 只返回一段数据的流, 模拟数据被分成多个 TCP 包到达
+struct Chunked {
+    chunks: Vec<Vec<u8>>,
+}
+
+impl AsyncRead for Chunked {
+    fn poll_read(mut self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        if self.chunks.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let chunk = &mut self.chunks[0];
+        let n = chunk.len().min(buf.len());
+        buf[..n].copy_from_slice(&chunk[..n]);
+        chunk.drain(..n);
+        if chunk.is_empty() {
+            self.chunks.remove(0);
+        }
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl AsyncWrite for Chunked {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+proptest! {
+    #[test]
+    fn parse_encoded_frame(frame in frame()) {
+        let data = encode(&frame);
+
+        let mut src = Cursor::new(&data[..]);
+        Frame::check(&mut src).unwrap();
+        prop_assert_eq!(src.position() as usize, data.len());
+
+        prop_assert_eq!(&Frame::parse(&mut Cursor::new(&data[..])).unwrap(), &frame);
+        prop_assert_eq!(&Frame::parse_shared(&Bytes::from(data)).unwrap(), &frame);
+    }
+
+    #[test]
+    fn decode_split_frame(frame in frame(), cuts in prop::collection::vec(any::<usize>(), 0..8)) {
+        let data = encode(&frame);
+
+        // 最后一段数据到达之前帧都是不完整的
+        let mut codec = RespCodec::new();
+        let mut buf = BytesMut::new();
+        let mut fed = 0;
+        for chunk in split_at(&data, cuts) {
+            fed += chunk.len();
+            buf.extend_from_slice(&chunk);
+
+            let decoded = codec.decode(&mut buf).unwrap();
+            if fed < data.len() {
+                prop_assert_eq!(decoded, None);
+            } else {
+                prop_assert_eq!(decoded, Some(frame));
+                prop_assert!(buf.is_empty());
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn read_split_frames(frames in prop::collection::vec(frame(), 1..4), cuts in prop::collection::vec(any::<usize>(), 0..16)) {
+        let data: Vec<u8> = frames.iter().flat_map(encode).collect();
+        // 读到 0 字节表示 EOF, 所以去掉空的段
+        let chunks = split_at(&data, cuts).into_iter().filter(|chunk| !chunk.is_empty()).collect();
+        let stream = Chunked { chunks };
+        let mut framed = Framed::new(stream, RespCodec::new());
+
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let decoded = rt.block_on(async {
+            let mut decoded = vec![];
+            while let Some(frame) = framed.read_frame().await.unwrap() {
+                decoded.push(frame);
+            }
+            decoded
+        });
+        prop_assert_eq!(decoded, frames);
+    }
+
+    #[test]
+    fn decode_garbage_never_panics(data in prop::collection::vec(any::<u8>(), 0..256)) {
+        let mut codec = RespCodec::new();
+        let mut buf = BytesMut::from(&data[..]);
+        while let Ok(Some(_)) = codec.decode(&mut buf) {}
+    }
+
+    #[test]
+    fn decode_corrupted_never_panics(frame in frame(), flips in prop::collection::vec((any::<usize>(), any::<u8>()), 1..4)) {
+        // 随机的字节大多会被当作 inline 命令, 修改合法帧中的几个字节更容易覆盖到各种类型的解析
+        let mut data = encode(&frame);
+        for (pos, byte) in flips {
+            let len = data.len();
+            data[pos % len] = byte;
+        }
+
+        let mut codec = RespCodec::new();
+        let mut buf = BytesMut::from(&data[..]);
+        while let Ok(Some(_)) = codec.decode(&mut buf) {}
+    }
+}
+
+
+// `data` 是否位于 `buf` 原来的内存中, 即没有被复制
+fn points_into(data: &Bytes, buf: &Range<*const u8>) -> bool {
+    buf.start <= data.as_ptr() && data.as_ptr() as usize + data.len() <= buf.end as usize
+}
+
+#[test]
+fn decode_bulk_without_copy() {
+    let mut codec = RespCodec::new();
+
+    let mut buf = BytesMut::from(&b"$5\r\nhello\r\n"[..]);
+    let range = buf.as_ptr_range();
+    match codec.decode(&mut buf).unwrap() {
+        Some(Frame::Bulk(data)) => {
+            assert_eq!(data, "hello");
+            assert!(points_into(&data, &range));
+        }
+        frame => panic!("unexpected {:?}", frame),
+    }
+
+    // 命令中 SET 的值同样直接取自读缓冲区
+    let mut buf = BytesMut::from(&b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$5\r\nvalue\r\n"[..]);
+    let range = buf.as_ptr_range();
+    let frame = codec.decode(&mut buf).unwrap().unwrap();
+    match Command::from_frame(frame).unwrap() {
+        Command::Set(set) => {
+            assert_eq!(set.value(), "value");
+            assert!(points_into(set.value(), &range));
+        }
+        command => panic!("unexpected {:?}", command),
+    }
+}
+
+// 解码出错时返回的 io 错误的类型
+fn io_error_kind(e: crate::relational::Error) -> io::ErrorKind {
+    e.downcast::<io::Error>().unwrap().kind()
+}
+
+#[test]
+fn length_delimited_rejects_oversized_frame() {
+    let mut codec = LengthDelimitedCodec::with_max_frame_length(16);
+
+    let mut buf = BytesMut::from(&b"\x00\x00\x00\x03abc"[..]);
+    assert_eq!(codec.decode(&mut buf).unwrap(), Some(Bytes::from("abc")));
+
+    // 声明了将近 4 GB 的帧头不能让缓冲区预留这么多空间
+    let mut buf = BytesMut::from(&b"\xff\xff\xff\xf0"[..]);
+    let err = LengthDelimitedCodec::new().decode(&mut buf).unwrap_err();
+    assert_eq!(io_error_kind(err), io::ErrorKind::InvalidData);
+    assert!(buf.capacity() < 1024);
+
+    let mut buf = BytesMut::from(&b"\x00\x00\x00\x11"[..]);
+    assert_eq!(io_error_kind(codec.decode(&mut buf).unwrap_err()), io::ErrorKind::InvalidData);
+
+    let mut dst = WriteBuf::new();
+    assert!(codec.encode(Bytes::from(vec![0; 17]), &mut dst).is_err());
+}
+
+#[test]
+fn lines_codec_rejects_long_lines() {
+    let mut codec = LinesCodec::with_max_length(4);
+
+    let mut buf = BytesMut::from(&b"abcd\r\nab"[..]);
+    assert_eq!(codec.decode(&mut buf).unwrap(), Some("abcd".to_string()));
+    assert_eq!(codec.decode(&mut buf).unwrap(), None);
+
+    // 还没有收到换行符时就已经超过了限制
+    buf.extend_from_slice(b"cdef");
+    assert_eq!(io_error_kind(codec.decode(&mut buf).unwrap_err()), io::ErrorKind::InvalidData);
+
+    let mut codec = LinesCodec::with_max_length(4);
+    let mut buf = BytesMut::from(&b"abcde\n"[..]);
+    assert_eq!(io_error_kind(codec.decode(&mut buf).unwrap_err()), io::ErrorKind::InvalidData);
+
+    let mut buf = BytesMut::from(&b"abcde"[..]);
+    assert_eq!(io_error_kind(codec.decode_eof(&mut buf).unwrap_err()), io::ErrorKind::InvalidData);
+}
+
+// 依次解码 `input` 中的所有 inline 命令
+fn decode_inline(input: &str) -> crate::relational::Result<Vec<Frame>> {
+    let mut codec = RespCodec::new();
+    let mut buf = BytesMut::from(input.as_bytes());
+    let mut frames = vec![];
+    while let Some(frame) = codec.decode(&mut buf)? {
+        frames.push(frame);
+    }
+    Ok(frames)
+}
+
+fn command(args: &[&[u8]]) -> Frame {
+    Frame::Array(args.iter().map(|arg| Frame::Bulk(Bytes::copy_from_slice(arg))).collect())
+}
+
+#[test]
+fn decode_inline_quotes_and_escapes() {
+    let frames = decode_inline("SET key \"hello world\"\r\nSET k \"a\\x41\\n\\\"b\" 'it\\'s'\r\nECHO \"\\xZZ\"\n").unwrap();
+    assert_eq!(frames, [
+        command(&[b"SET", b"key", b"hello world"]),
+        command(&[b"SET", b"k", b"aA\n\"b", b"it's"]),
+        // 不是合法的十六进制转义时 \x 按普通字符处理
+        command(&[b"ECHO", b"xZZ"]),
+    ]);
+}
+
+#[test]
+fn decode_inline_skips_empty_lines() {
+    let frames = decode_inline("\r\n   \r\n\nPING\r\n\r\n").unwrap();
+    assert_eq!(frames, [command(&[b"PING"])]);
+
+    // 只有空行时没有帧, 也不会留下数据
+    let mut codec = RespCodec::new();
+    let mut buf = BytesMut::from(&b"\r\n\r\n"[..]);
+    assert_eq!(codec.decode(&mut buf).unwrap(), None);
+    assert!(buf.is_empty());
+}
+
+#[test]
+fn decode_inline_rejects_unbalanced_quotes() {
+    for input in &["SET k \"abc\r\n", "SET k 'abc\r\n", "SET k \"abc\"def\r\n", "SET k 'abc'def\r\n"] {
+        let err = decode_inline(input).unwrap_err();
+        assert_eq!(err.to_string(), "protocol error; unbalanced quotes in request", "{:?}", input);
+    }
+}
+
+#[test]
+fn decode_inline_only_without_resp_type_byte() {
+    // 与 redis 不同, 任何 RESP 类型字节开头的数据都按 RESP 解析
+    assert_eq!(decode_inline("+foo\r\n").unwrap(), [Frame::Simple("foo".to_string())]);
+    assert_eq!(decode_inline("foo +bar\r\n").unwrap(), [command(&[b"foo", b"+bar"])]);
+}
+
+#[test]
+fn debug_matches_redis_cli() {
+    let frame = Frame::Array(vec![
+        Frame::Simple("OK".to_string()),
+        Frame::Bulk(Bytes::from("OK")),
+        Frame::Integer(1),
+        Frame::Null,
+        Frame::Array(vec![Frame::Error("ERR boom".to_string()), Frame::Double(1.5)]),
+    ]);
+    assert_eq!(format!("{:?}", frame), format!("{}", frame));
+    assert_eq!(format!("{:?}", frame), "1) OK\n2) \"OK\"\n3) (integer) 1\n4) (nil)\n5) 1) (error) ERR boom\n   2) (double) 1.5");
+}
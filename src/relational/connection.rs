@@ -1,49 +1,433 @@
 use tokio::net::TcpStream;
-use mini_redis::{Frame, Result};
-use bytes::BytesMut;
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use bytes::{Buf, Bytes};
+use futures::stream::Stream;
+use std::io::{self, Cursor};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use super::{Error, Result};
+use super::codec::RespCodec;
+use super::frame_enum::{self, Frame, Limits};
+use super::framed::Framed;
 
-pub struct Connection {
-    stream: TcpStream,
-    buffer: BytesMut,
+/// 链接上使用的协议版本
+///
+/// 新建立的链接总是使用 RESP2, 只有当客户端发送 `HELLO 3` 之后才切换到 RESP3. 读取时两种协议的帧都能被解析,
+/// 写入时如果对方只支持 RESP2, RESP3 特有的帧会被转换为 RESP2 中对应的类型, 比如 map 被展开为数组.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    Resp2,
+    Resp3,
 }
 
-impl Connection {
-    pub fn new(stream: TcpStream) -> Connection {
+impl Protocol {
+    // HELLO 命令中使用的协议版本号
+    fn version(self) -> i64 {
+        match self {
+            Protocol::Resp2 => 2,
+            Protocol::Resp3 => 3,
+        }
+    }
+}
+
+/// 读写 Redis 协议帧的链接
+///
+/// 缓冲区的管理由 `Framed` 完成, 帧的编解码由 `RespCodec` 完成, 所以链接可以建立在任意的
+/// `AsyncRead + AsyncWrite` 流之上, 默认是 `TcpStream`.
+pub struct Connection<T = TcpStream> {
+    framed: Framed<T, RespCodec>,
+    // 被提前丢弃的 `BulkBody` 还没有读取的数据字节数, 下一次读取前需要先跳过它们
+    discard: usize,
+    // 被提前丢弃的 `BulkBody` 结尾的 \r\n 还没有读取, 跳过数据后需要检查它
+    discard_crlf: bool,
+    // `read_pipeline` 在批次中间遇到的解码错误, 在下一次读取时返回
+    error: Option<Error>,
+}
+
+/// `read_streaming` 读取到的内容
+pub enum Streamed<'a, T> {
+    /// 一个完整的帧
+    Frame(Frame),
+    /// 一个大 bulk 的头部与主体, 主体需要通过 `BulkBody` 读取
+    Bulk(BulkHeader, BulkBody<'a, T>),
+}
+
+/// 大 bulk 的头部
+#[derive(Debug)]
+pub struct BulkHeader {
+    /// bulk 之前的元素, 比如 `SET key value` 中的 `SET` 与 `key`. 如果帧本身就是一个 bulk 则为空
+    pub prefix: Vec<Frame>,
+    /// bulk 数据的字节数
+    pub len: usize,
+}
+
+/// 大 bulk 的主体
+///
+/// 同时实现了 `Stream<Item = Result<Bytes>>` 与 `AsyncRead`, 数据按从 socket 中读取到的块依次返回,
+/// 不会在内存中缓冲整个 bulk. 在读完之前被 drop 时, 剩余的数据会在下一次读取帧时被跳过.
+pub struct BulkBody<'a, T> {
+    conn: &'a mut Connection<T>,
+    // 还没有读取的数据字节数
+    remaining: usize,
+    // 还没有读取的 \r\n 字节数
+    crlf: usize,
+}
+
+impl<T> Connection<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn new(stream: T) -> Connection<T> {
+        Connection::with_limits(stream, Limits::default())
+    }
+
+    /// 创建一个按指定 `limits` 读取帧的链接
+    pub fn with_limits(stream: T, limits: Limits) -> Connection<T> {
         Connection {
-            stream,
-            // 默认分配4kb容量给buffer
-            buffer: BytesMut::with_capacity(4 * 1024),
+            framed: Framed::new(stream, RespCodec::with_limits(limits)),
+            discard: 0,
+            discard_crlf: false,
+            error: None,
         }
     }
+
+    /// 返回链接当前使用的协议版本
+    pub fn protocol(&self) -> Protocol {
+        self.framed.codec().protocol()
+    }
+
+    /// 切换链接使用的协议版本, 之后写入的帧都按新的协议编码
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.framed.codec_mut().set_protocol(protocol);
+    }
+
     /// 从链接中读取一个帧，如果EOF到就返回 None
-    pub async fn read_frame(&mut self) -> Result<Option<Frame>>{
+    ///
+    /// 如果对方发送了无效的或超过 `Limits` 的帧, 先回复一个 `ERR Protocol error` 错误帧再关闭写方向, 然后返回错误.
+    /// 此时缓冲区中的数据已经不可信, 调用者应当丢弃这个链接.
+    pub async fn read_frame(&mut self) -> Result<Option<Frame>> {
+        self.take_error().await?;
+        self.discard_pending().await?;
+
+        match self.framed.read_frame().await {
+            Err(e) => Err(self.decode_error(e).await),
+            res => res,
+        }
+    }
+
+    /// 读取一批流水线 (pipeline) 请求
+    ///
+    /// 等待至少一个完整的帧, 然后把读缓冲区中已经完整收到的帧都解码出来, 按顺序返回. 客户端可以不等回复就连续发送
+    /// 多个命令, 服务端按顺序执行后用 `queue_frame` 缓冲所有回复, 最后 `flush` 一次写出. EOF 时返回 None.
+    ///
+    /// 如果批次中间遇到无效的帧, 先返回之前的帧, 错误在下一次读取时返回 (同时回复 `ERR Protocol error`).
+    pub async fn read_pipeline(&mut self) -> Result<Option<Vec<Frame>>> {
+        let frame = match self.read_frame().await? {
+            Some(frame) => frame,
+            None => return Ok(None),
+        };
+
+        let mut frames = vec![frame];
         loop {
-            // 尝试从缓冲区中解析一个帧，如果buffer中有足够的数据那么帧就返回
-            if let Some(frame) = self.parse_frame()? {
-                return Ok(Some(frame));
+            match self.framed.try_read_frame() {
+                Ok(Some(frame)) => frames.push(frame),
+                Ok(None) => break,
+                // 出错的帧与之后的数据都留在缓冲区中, 不再继续解码. 下一次读取时回复错误并关闭链接
+                Err(e) => {
+                    self.error = Some(e);
+                    break;
+                }
+            }
+        }
+
+        Ok(Some(frames))
+    }
+
+    /// 流式地读取一个帧, 适用于可能包含很大 bulk 的请求, 比如上传一个几 MB 的 `SET`
+    ///
+    /// 如果帧是一个长度不小于 `threshold` 的 bulk, 或者是最后一个元素为这样的 bulk 的数组, 只要收到 bulk 之前的部分
+    /// 就返回 `Streamed::Bulk`, bulk 的数据通过 `BulkBody` 边读边消费, 可以直接写到文件或者转发出去. 其它帧与
+    /// `read_frame` 一样完整地读取后返回 `Streamed::Frame`.
+    pub async fn read_streaming(&mut self, threshold: usize) -> Result<Option<Streamed<'_, T>>> {
+        use frame_enum::Error::Incomplete;
+
+        self.take_error().await?;
+        self.discard_pending().await?;
+
+        // 先确定帧是否需要流式读取, 得到头部的字节数与 bulk 的长度
+        let head = loop {
+            let limits = *self.framed.codec().limits();
+            let mut buf = Cursor::new(&self.framed.read_buffer()[..]);
+
+            match Frame::check_bulk_head(&mut buf, threshold, &limits) {
+                Ok(Some(len)) => break Some((buf.position() as usize, len)),
+                Ok(None) => break None,
+                Err(Incomplete) if self.framed.read_buffer().len() > limits.max_buffer => {
+                    let e = frame_enum::Error::from("protocol error; too big request");
+                    return Err(self.protocol_error(e.into()).await);
+                }
+                Err(Incomplete) => {}
+                Err(e) => return Err(self.protocol_error(e.into()).await),
             }
 
-            // 如果没有足够的数据读取到一个帧中,那么尝试从socket中读取更多的数据
-            // 如果成功了，一定数量的字节被返回, 0 表时到了流的末尾了.
-            if 0 == self.stream.read_buf(&mut self.buffer).await? {
-                // 远程关闭了链接, 为了彻底关闭,读缓冲区中应该没有数据了,如果还存在数据那说明对等方在发送帧时关闭了socket
-                return if self.buffer.is_empty() {
+            if 0 == self.framed.read_more().await? {
+                return if self.framed.read_buffer().is_empty() {
                     Ok(None)
-                }else {
+                } else {
                     Err("connection reset by peer".into())
+                };
+            }
+        };
+
+        match head {
+            Some((head_len, len)) => {
+                let head = self.framed.read_buffer_mut().split_to(head_len).freeze();
+                let prefix = Frame::parse_bulk_head(&head)?;
+
+                let body = BulkBody { conn: self, remaining: len, crlf: 2 };
+                Ok(Some(Streamed::Bulk(BulkHeader { prefix, len }, body)))
+            }
+            None => Ok(self.read_frame().await?.map(Streamed::Frame)),
+        }
+    }
+
+    // 跳过被提前丢弃的 `BulkBody` 剩余的数据. 与读完的 bulk 一样, 结尾必须是 \r\n, 否则回复协议错误
+    async fn discard_pending(&mut self) -> Result<()> {
+        while self.discard > 0 {
+            let buf = self.framed.read_buffer_mut();
+            if buf.is_empty() {
+                if 0 == self.framed.read_more().await? {
+                    return Err("connection reset by peer".into());
                 }
+                continue;
             }
 
+            let n = self.discard.min(buf.len());
+            buf.advance(n);
+            self.discard -= n;
         }
+
+        if self.discard_crlf {
+            while self.framed.read_buffer().len() < 2 {
+                if 0 == self.framed.read_more().await? {
+                    return Err("connection reset by peer".into());
+                }
+            }
+
+            self.discard_crlf = false;
+            if &self.framed.read_buffer()[..2] != b"\r\n" {
+                let e = frame_enum::Error::from("protocol error; invalid frame format");
+                return Err(self.decode_error(e.into()).await);
+            }
+            self.framed.read_buffer_mut().advance(2);
+        }
+        Ok(())
     }
 
-    /// 写一个帧到链接中
-    pub async fn write_frame(&mut self, frame:&Frame) -> Result<()> {
+    // 返回 `read_pipeline` 留下的错误
+    async fn take_error(&mut self) -> Result<()> {
+        match self.error.take() {
+            Some(e) => Err(self.decode_error(e).await),
+            None => Ok(()),
+        }
+    }
 
+    // 解码出错: 协议错误先回复对方, 其它错误 (比如 IO 错误) 直接返回
+    async fn decode_error(&mut self, e: Error) -> Error {
+        if e.is::<frame_enum::Error>() {
+            self.protocol_error(e).await
+        } else {
+            e
+        }
     }
 
-    fn parse_frame() -> Result<()>{
+    // 对方发送了无效的帧: 先回复一个 `ERR Protocol error` 错误帧再关闭写方向, 返回原来的错误
+    async fn protocol_error(&mut self, e: Error) -> Error {
+        // 帧解析的错误都以 "protocol error; " 开头, 按 redis 的格式回复
+        let msg = e.to_string();
+        let msg = msg.trim_start_matches("protocol error; ");
+        let response = Frame::Error(format!("ERR Protocol error: {}", msg));
+        // 对方可能已经关闭了链接, 回复失败时仍然返回原来的协议错误
+        if self.write_frame(&response).await.is_ok() {
+            let _ = self.framed.get_mut().shutdown().await;
+        }
+        e
+    }
 
+    /// 写一个帧到链接中, 写完整个帧后 flush 一次
+    pub async fn write_frame(&mut self, frame: &Frame) -> Result<()> {
+        self.framed.write_frame(frame).await
     }
-}
\ No newline at end of file
+
+    /// 把一个帧放入写缓冲区, 不会写到 socket 中, 需要调用 `flush` 才会写出
+    pub fn queue_frame(&mut self, frame: &Frame) -> Result<()> {
+        self.framed.feed(frame)
+    }
+
+    /// 把 `queue_frame` 缓冲的所有帧一次写到 socket 中
+    pub async fn flush(&mut self) -> Result<()> {
+        self.framed.flush().await
+    }
+
+    /// 服务端处理 `HELLO [protover]` 握手
+    ///
+    /// 如果 `frame` 不是 `HELLO` 命令则返回 `false`, 交给调用者按普通命令处理. 否则切换协议版本并写入回复,
+    /// 回复是一个描述服务端的 map, 在 RESP2 下会被展开为数组. 不支持的版本号返回 `NOPROTO` 错误, 协议保持不变.
+    /// 回复与 `write_frame` 一样立即 flush, 之前用 `queue_frame` 缓冲的回复按原来的协议先写出.
+    pub async fn handle_hello(&mut self, frame: &Frame) -> Result<bool> {
+        let parts = match frame {
+            Frame::Array(parts) if !parts.is_empty() => parts,
+            _ => return Ok(false),
+        };
+
+        match &parts[0] {
+            Frame::Bulk(name) if name.eq_ignore_ascii_case(b"hello") => {}
+            _ => return Ok(false),
+        }
+
+        // 没有指定版本号时保持当前协议, 只返回服务端信息
+        let protocol = match parts.get(1) {
+            None => Some(self.protocol()),
+            Some(Frame::Bulk(version)) if &version[..] == b"2" => Some(Protocol::Resp2),
+            Some(Frame::Bulk(version)) if &version[..] == b"3" => Some(Protocol::Resp3),
+            Some(_) => None,
+        };
+
+        let response = match protocol {
+            Some(protocol) => {
+                self.set_protocol(protocol);
+
+                Frame::Map(vec![
+                    (Frame::Bulk(Bytes::from("server")), Frame::Bulk(Bytes::from("tokio-cn-doc"))),
+                    (Frame::Bulk(Bytes::from("version")), Frame::Bulk(Bytes::from(env!("CARGO_PKG_VERSION")))),
+                    (Frame::Bulk(Bytes::from("proto")), Frame::Integer(protocol.version())),
+                    (Frame::Bulk(Bytes::from("mode")), Frame::Bulk(Bytes::from("standalone"))),
+                    (Frame::Bulk(Bytes::from("role")), Frame::Bulk(Bytes::from("master"))),
+                    (Frame::Bulk(Bytes::from("modules")), Frame::array()),
+                ])
+            }
+            None => Frame::Error("NOPROTO unsupported protocol version".to_string()),
+        };
+
+        self.write_frame(&response).await?;
+        Ok(true)
+    }
+
+    /// 客户端发送 `HELLO` 请求切换到指定的协议版本
+    ///
+    /// 服务端接受时返回它的回复并切换本链接的协议版本. 老版本的服务端不认识 `HELLO` 命令会返回错误,
+    /// 此时链接继续使用 RESP2, 错误被返回给调用者.
+    pub async fn hello(&mut self, protocol: Protocol) -> Result<Frame> {
+        let mut frame = Frame::array();
+        frame.push_bulk(Bytes::from("HELLO"));
+        frame.push_bulk(Bytes::from(protocol.version().to_string()));
+        self.write_frame(&frame).await?;
+
+        match self.read_frame().await? {
+            Some(Frame::Error(msg)) => Err(msg.into()),
+            Some(response) => {
+                self.set_protocol(protocol);
+                Ok(response)
+            }
+            None => Err("connection reset by peer".into()),
+        }
+    }
+}
+
+impl<T> BulkBody<'_, T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    /// 还没有读取的数据字节数
+    pub fn remaining(&self) -> usize {
+        self.remaining
+    }
+
+    // 确保读缓冲区中有可以返回的数据. 返回 false 表示数据已经读完, 并且结尾的 \r\n 也已经被消费
+    fn poll_fill(&mut self, cx: &mut Context<'_>) -> Poll<Result<bool>> {
+        loop {
+            let buf = self.conn.framed.read_buffer_mut();
+
+            if self.remaining > 0 && !buf.is_empty() {
+                return Poll::Ready(Ok(true));
+            }
+
+            if self.remaining == 0 {
+                if self.crlf == 0 {
+                    return Poll::Ready(Ok(false));
+                }
+                if buf.len() >= 2 {
+                    if &buf[..2] != b"\r\n" {
+                        return Poll::Ready(Err(frame_enum::Error::from("protocol error; invalid frame format").into()));
+                    }
+                    buf.advance(2);
+                    self.crlf = 0;
+                    return Poll::Ready(Ok(false));
+                }
+            }
+
+            match self.conn.framed.poll_read_more(cx) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err("connection reset by peer".into())),
+                Poll::Ready(Ok(_)) => {}
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e.into())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl<T> Stream for BulkBody<'_, T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    type Item = Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes>>> {
+        match self.poll_fill(cx) {
+            Poll::Ready(Ok(true)) => {
+                let n = self.remaining.min(self.conn.framed.read_buffer().len());
+                self.remaining -= n;
+
+                // 直接从读缓冲区中切出数据, 不会复制
+                let chunk = self.conn.framed.read_buffer_mut().split_to(n).freeze();
+                Poll::Ready(Some(Ok(chunk)))
+            }
+            Poll::Ready(Ok(false)) => Poll::Ready(None),
+            Poll::Ready(Err(e)) => Poll::Ready(Some(Err(e))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<T> AsyncRead for BulkBody<'_, T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, dst: &mut [u8]) -> Poll<io::Result<usize>> {
+        match self.poll_fill(cx) {
+            Poll::Ready(Ok(true)) => {
+                let buf = self.conn.framed.read_buffer();
+                let n = self.remaining.min(buf.len()).min(dst.len());
+                dst[..n].copy_from_slice(&buf[..n]);
+
+                self.conn.framed.read_buffer_mut().advance(n);
+                self.remaining -= n;
+                Poll::Ready(Ok(n))
+            }
+            Poll::Ready(Ok(false)) => Poll::Ready(Ok(0)),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, e))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<T> Drop for BulkBody<'_, T> {
+    fn drop(&mut self) {
+        // 没有读完的数据留给下一次读取时跳过, 结尾的 \r\n 留给下一次读取时检查
+        self.conn.discard = self.remaining;
+        self.conn.discard_crlf = self.crlf > 0;
+    }
+}
+
+#[cfg(test)]
+mod tests;
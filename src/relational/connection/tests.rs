@@ -0,0 +1,347 @@
+// `Connection` 的测试: 用一个内存中的流代替 socket, 按段输入数据并记录写出的回复.
+use bytes::{Buf, Bytes};
+use std::future::Future;
+use std::io::{self, IoSlice};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
+use futures::StreamExt;
+use tokio::io::AsyncReadExt;
+use super::{Connection, Protocol, Streamed};
+use crate::relational::frame_enum::{Frame, Limits};
+
+// 对方收到的数据
+#[derive(Default)]
+struct Peer {
+    written: Vec<u8>,
+    // poll_write 与 poll_write_buf 被调用的次数, 即 socket 上发生的写的次数
+    writes: usize,
+    shutdown: bool,
+}
+
+// 每次 poll_read 只返回一段数据的流, 写入的数据记录在 `Peer` 中
+struct Mock {
+    chunks: Vec<Vec<u8>>,
+    peer: Arc<Mutex<Peer>>,
+}
+
+impl AsyncRead for Mock {
+    fn poll_read(mut self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        if self.chunks.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let chunk = &mut self.chunks[0];
+        let n = chunk.len().min(buf.len());
+        buf[..n].copy_from_slice(&chunk[..n]);
+        chunk.drain(..n);
+        if chunk.is_empty() {
+            self.chunks.remove(0);
+        }
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl AsyncWrite for Mock {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let mut peer = self.peer.lock().unwrap();
+        peer.written.extend_from_slice(buf);
+        peer.writes += 1;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    // 与 `TcpStream` 一样用一次 writev 写出 `buf` 中的所有块
+    fn poll_write_buf<B: Buf>(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &mut B) -> Poll<io::Result<usize>> {
+        let mut slices = [IoSlice::new(&[]); 64];
+        let cnt = buf.bytes_vectored(&mut slices);
+
+        let mut peer = self.peer.lock().unwrap();
+        let n: usize = slices[..cnt].iter().map(|slice| slice.len()).sum();
+        slices[..cnt].iter().for_each(|slice| peer.written.extend_from_slice(slice));
+        peer.writes += 1;
+
+        buf.advance(n);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.peer.lock().unwrap().shutdown = true;
+        Poll::Ready(Ok(()))
+    }
+}
+
+// 创建一个依次读到 `chunks` 的链接
+fn connect(chunks: &[&[u8]]) -> (Connection<Mock>, Arc<Mutex<Peer>>) {
+    connect_with_limits(Limits::default(), chunks)
+}
+
+fn connect_with_limits(limits: Limits, chunks: &[&[u8]]) -> (Connection<Mock>, Arc<Mutex<Peer>>) {
+    let peer = Arc::new(Mutex::new(Peer::default()));
+    let stream = Mock { chunks: chunks.iter().map(|chunk| chunk.to_vec()).collect(), peer: peer.clone() };
+    (Connection::with_limits(stream, limits), peer)
+}
+
+fn block_on<F: Future>(future: F) -> F::Output {
+    tokio::runtime::Runtime::new().unwrap().block_on(future)
+}
+
+// 取出对方到目前为止收到的数据
+fn take_written(peer: &Mutex<Peer>) -> String {
+    String::from_utf8(std::mem::take(&mut peer.lock().unwrap().written)).unwrap()
+}
+
+fn bulks(args: &[&str]) -> Vec<Frame> {
+    args.iter().map(|arg| Frame::Bulk(Bytes::from(arg.to_string()))).collect()
+}
+
+fn command(args: &[&str]) -> Frame {
+    Frame::Array(bulks(args))
+}
+
+#[test]
+fn pipeline_reads_batch_and_coalesces_replies() {
+    // 4 个命令在同一段数据中到达. `GET b` 的回复足够大, 在写缓冲区中是单独的块, 需要 writev 才能一次写出
+    let big = "x".repeat(2048);
+    let mut input = b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n*2\r\n$3\r\nGET\r\n$1\r\na\r\n".to_vec();
+    input.extend_from_slice(format!("*3\r\n$3\r\nSET\r\n$1\r\nb\r\n$2048\r\n{}\r\n*2\r\n$3\r\nGET\r\n$1\r\nb\r\n", big).as_bytes());
+    let (mut conn, peer) = connect(&[&input]);
+
+    block_on(async {
+        let frames = conn.read_pipeline().await.unwrap().unwrap();
+        assert_eq!(frames, [
+            command(&["SET", "a", "1"]),
+            command(&["GET", "a"]),
+            command(&["SET", "b", &big]),
+            command(&["GET", "b"]),
+        ]);
+
+        // 所有回复缓冲后通过一次写发出
+        for reply in &[Frame::Simple("OK".to_string()), Frame::Bulk(Bytes::from("1")), Frame::Simple("OK".to_string())] {
+            conn.queue_frame(reply).unwrap();
+        }
+        conn.queue_frame(&Frame::Bulk(Bytes::from(big.clone()))).unwrap();
+        assert_eq!(peer.lock().unwrap().writes, 0);
+        conn.flush().await.unwrap();
+
+        assert!(conn.read_pipeline().await.unwrap().is_none());
+    });
+
+    let peer = peer.lock().unwrap();
+    assert_eq!(peer.writes, 1);
+    assert_eq!(String::from_utf8_lossy(&peer.written), format!("+OK\r\n$1\r\n1\r\n+OK\r\n$2048\r\n{}\r\n", big));
+}
+
+#[test]
+fn pipeline_error_is_returned_on_next_read() {
+    // 第二个帧的 bulk 后面不是 \r\n, 它之后的 SET 不能被当作正常的命令执行
+    let (mut conn, peer) = connect(&[b"*1\r\n$3\r\nGET\r\n*1\r\n$3\r\nabcXY*1\r\n$3\r\nSET\r\n"]);
+
+    block_on(async {
+        assert_eq!(conn.read_pipeline().await.unwrap(), Some(vec![command(&["GET"])]));
+        assert!(peer.lock().unwrap().written.is_empty());
+
+        assert!(conn.read_pipeline().await.is_err());
+    });
+
+    let peer = peer.lock().unwrap();
+    assert!(peer.written.starts_with(b"-ERR Protocol error: "));
+    assert!(peer.shutdown);
+}
+
+// 读取 `input` 时遇到协议错误: 回复 `-ERR Protocol error: <msg>` 后关闭写方向
+fn assert_protocol_error(limits: Limits, input: &[u8], msg: &str) {
+    let (mut conn, peer) = connect_with_limits(limits, &[input]);
+    assert!(block_on(conn.read_frame()).is_err());
+
+    let peer = peer.lock().unwrap();
+    assert_eq!(String::from_utf8_lossy(&peer.written), format!("-ERR Protocol error: {}\r\n", msg));
+    assert!(peer.shutdown);
+}
+
+#[test]
+fn limits_reply_protocol_error() {
+    let limits = Limits { max_bulk_len: 16, max_array_len: 4, max_depth: 2, max_buffer: 64, max_inline_len: 16 };
+
+    assert_protocol_error(limits, b"$17\r\n", "invalid bulk length");
+    assert_protocol_error(limits, b"*5\r\n", "invalid multibulk length");
+    assert_protocol_error(limits, b"*1\r\n*1\r\n*1\r\n", "too many nested aggregates");
+    assert_protocol_error(limits, b"abcdefghijklmnopq", "too big inline request");
+    assert_protocol_error(limits, b"abcdefghijklmnopq\r\n", "too big inline request");
+
+    // 每个元素都没有超过限制, 但是还没有收完的帧已经超过了缓冲区的限制
+    let mut input = b"*4\r\n".to_vec();
+    for _ in 0..3 {
+        input.extend_from_slice(b"$16\r\n0123456789abcdef\r\n");
+    }
+    assert_protocol_error(limits, &input, "too big request");
+
+    // 长度加上 \r\n 会溢出
+    let limits = Limits { max_bulk_len: usize::MAX, ..Limits::default() };
+    assert_protocol_error(limits, format!("${}\r\n", usize::MAX).as_bytes(), "invalid bulk length");
+}
+
+#[test]
+fn server_negotiates_protocol_with_hello() {
+    let input = [
+        &b"*2\r\n$5\r\nHELLO\r\n$1\r\n3\r\n"[..],
+        b"*2\r\n$5\r\nhello\r\n$1\r\n4\r\n",
+        b"*2\r\n$5\r\nHELLO\r\n$1\r\n2\r\n",
+        b"*1\r\n$4\r\nPING\r\n",
+    ];
+    let (mut conn, peer) = connect(&input);
+
+    block_on(async {
+        assert_eq!(conn.protocol(), Protocol::Resp2);
+
+        // 切换到 RESP3 后回复是一个 map, double 按 RESP3 的格式写出
+        let hello = conn.read_frame().await.unwrap().unwrap();
+        assert!(conn.handle_hello(&hello).await.unwrap());
+        assert_eq!(conn.protocol(), Protocol::Resp3);
+        assert!(take_written(&peer).starts_with("%6\r\n$6\r\nserver\r\n"));
+        conn.write_frame(&Frame::Double(1.5)).await.unwrap();
+        assert_eq!(take_written(&peer), ",1.5\r\n");
+
+        // 不支持的版本号返回错误, 协议保持不变
+        let hello = conn.read_frame().await.unwrap().unwrap();
+        assert!(conn.handle_hello(&hello).await.unwrap());
+        assert_eq!(conn.protocol(), Protocol::Resp3);
+        assert_eq!(take_written(&peer), "-NOPROTO unsupported protocol version\r\n");
+
+        // 降级回 RESP2 后 map 展开为数组, double 写为 bulk 字符串
+        let hello = conn.read_frame().await.unwrap().unwrap();
+        assert!(conn.handle_hello(&hello).await.unwrap());
+        assert_eq!(conn.protocol(), Protocol::Resp2);
+        assert!(take_written(&peer).starts_with("*12\r\n$6\r\nserver\r\n"));
+        conn.write_frame(&Frame::Double(1.5)).await.unwrap();
+        assert_eq!(take_written(&peer), "$3\r\n1.5\r\n");
+
+        // 其它命令交给调用者处理
+        let ping = conn.read_frame().await.unwrap().unwrap();
+        assert!(!conn.handle_hello(&ping).await.unwrap());
+        assert!(take_written(&peer).is_empty());
+    });
+}
+
+#[test]
+fn client_hello_falls_back_to_resp2() {
+    // 服务端接受 HELLO 3
+    let (mut conn, peer) = connect(&[b"%1\r\n$5\r\nproto\r\n:3\r\n"]);
+    let response = block_on(conn.hello(Protocol::Resp3)).unwrap();
+    assert_eq!(response, Frame::Map(vec![(Frame::Bulk(Bytes::from("proto")), Frame::Integer(3))]));
+    assert_eq!(conn.protocol(), Protocol::Resp3);
+    assert_eq!(take_written(&peer), "*2\r\n$5\r\nHELLO\r\n$1\r\n3\r\n");
+
+    // 老版本的服务端不认识 HELLO, 链接继续使用 RESP2
+    let (mut conn, _peer) = connect(&[b"-ERR unknown command 'HELLO'\r\n"]);
+    let err = block_on(conn.hello(Protocol::Resp3)).unwrap_err();
+    assert_eq!(err.to_string(), "ERR unknown command 'HELLO'");
+    assert_eq!(conn.protocol(), Protocol::Resp2);
+}
+
+#[test]
+fn stream_chunked_bulk_body() {
+    let input = [
+        &b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$10\r\n"[..],
+        b"01234",
+        b"56789\r",
+        b"\n*1\r\n$4\r\nPING\r\n",
+    ];
+    let (mut conn, _peer) = connect(&input);
+
+    block_on(async {
+        let chunks = match conn.read_streaming(8).await.unwrap().unwrap() {
+            Streamed::Bulk(header, mut body) => {
+                assert_eq!(header.prefix, bulks(&["SET", "k"]));
+                assert_eq!(header.len, 10);
+
+                let mut chunks = vec![];
+                while let Some(chunk) = body.next().await {
+                    chunks.push(chunk.unwrap());
+                }
+                assert_eq!(body.remaining(), 0);
+                chunks
+            }
+            Streamed::Frame(frame) => panic!("unexpected frame: {}", frame),
+        };
+        // 数据按到达的块返回, 不会等整个 bulk 收完
+        assert_eq!(chunks, [Bytes::from("01234"), Bytes::from("56789")]);
+
+        // 短的 bulk 完整地读取
+        match conn.read_streaming(8).await.unwrap().unwrap() {
+            Streamed::Frame(frame) => assert_eq!(frame, command(&["PING"])),
+            Streamed::Bulk(..) => panic!("unexpected bulk"),
+        }
+        assert!(conn.read_streaming(8).await.unwrap().is_none());
+    });
+}
+
+#[test]
+fn dropped_bulk_body_is_skipped() {
+    let input = [
+        &b"$10\r\n012"[..],
+        b"3456",
+        b"789\r\n*1\r\n$4\r\nPING\r\n",
+    ];
+    let (mut conn, _peer) = connect(&input);
+
+    block_on(async {
+        match conn.read_streaming(4).await.unwrap().unwrap() {
+            Streamed::Bulk(header, mut body) => {
+                assert!(header.prefix.is_empty());
+                assert_eq!(body.next().await.unwrap().unwrap(), Bytes::from("012"));
+                assert_eq!(body.remaining(), 7);
+            }
+            Streamed::Frame(frame) => panic!("unexpected frame: {}", frame),
+        }
+
+        // 没有读完的数据与结尾的 \r\n 被跳过
+        assert_eq!(conn.read_frame().await.unwrap(), Some(command(&["PING"])));
+    });
+}
+
+#[test]
+fn dropped_bulk_body_checks_crlf() {
+    // bulk 的数据后面不是 \r\n, 跳过数据后不能从错位的地方继续解析
+    let (mut conn, peer) = connect(&[b"$10\r\n012", b"3456789XY*1\r\n$4\r\nPING\r\n"]);
+
+    block_on(async {
+        match conn.read_streaming(4).await.unwrap().unwrap() {
+            Streamed::Bulk(_, mut body) => assert_eq!(body.next().await.unwrap().unwrap(), Bytes::from("012")),
+            Streamed::Frame(frame) => panic!("unexpected frame: {}", frame),
+        }
+
+        assert!(conn.read_frame().await.is_err());
+    });
+
+    let peer = peer.lock().unwrap();
+    assert_eq!(String::from_utf8_lossy(&peer.written), "-ERR Protocol error: invalid frame format\r\n");
+    assert!(peer.shutdown);
+}
+
+#[test]
+fn read_bulk_body_split_across_reads() {
+    // 每次只到达一个字节, 结尾的 \r 与 \n 也分开到达
+    let input = b"*2\r\n$3\r\nPUT\r\n$6\r\nabcdef\r\n*1\r\n$4\r\nPING\r\n";
+    let chunks: Vec<&[u8]> = input.chunks(1).collect();
+    let (mut conn, _peer) = connect(&chunks);
+
+    block_on(async {
+        match conn.read_streaming(4).await.unwrap().unwrap() {
+            Streamed::Bulk(header, mut body) => {
+                assert_eq!(header.prefix, bulks(&["PUT"]));
+
+                let mut data = vec![];
+                body.read_to_end(&mut data).await.unwrap();
+                assert_eq!(data, b"abcdef");
+            }
+            Streamed::Frame(frame) => panic!("unexpected frame: {}", frame),
+        }
+
+        assert_eq!(conn.read_frame().await.unwrap(), Some(command(&["PING"])));
+    });
+}
@@ -0,0 +1,42 @@
+//! shared-state 示例服务端中所有连接共享的数据库.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+
+use super::cmd::Command;
+use super::frame_enum::Frame;
+
+/// 所有连接共享的数据库
+///
+/// 值是从读缓冲区中切出来的 Bytes, 存入 HashMap 与写回客户端时都只增加引用计数, 不会复制数据
+pub type Db = Arc<Mutex<HashMap<String, Bytes>>>;
+
+/// 创建一个空的数据库
+pub fn new_db() -> Db {
+    Arc::new(Mutex::new(HashMap::new()))
+}
+
+/// 在数据库上执行一条命令, 返回回复的帧. 锁只在执行命令期间持有, 不会跨越 `.await`
+///
+/// # Panics
+///
+/// 如果命令不是 `GET` 或者 `SET` 则 panic
+pub fn execute(db: &Db, cmd: Command) -> Frame {
+    match cmd {
+        Command::Set(cmd) => {
+            let mut db = db.lock().unwrap();
+            db.insert(cmd.key().to_string(), cmd.value().clone());
+            Frame::Simple("OK".to_string())
+        }
+        Command::Get(cmd) => {
+            let db = db.lock().unwrap();
+            match db.get(cmd.key()) {
+                Some(value) => Frame::Bulk(value.clone()),
+                None => Frame::Null,
+            }
+        }
+        // 其它cmd 情况
+        cmd => panic!("unimplemented Command :{:?}", cmd),
+    }
+}
@@ -1,10 +1,821 @@
-use bytes::Bytes;
+//! Redis 协议帧的定义, 以及从字节数组中检查和解析帧的工具函数.
+use bytes::{Buf, Bytes};
+use std::convert::TryInto;
+use std::fmt;
+use std::io::Cursor;
+use std::num::TryFromIntError;
+use std::string::FromUtf8Error;
 
-enum Frame {
+/// Redis 协议中的一个帧
+///
+/// 帧仅由没有任何语义的数据组成, 命令的解析发生在更高的层级. 数组可以嵌套其它任意的帧,
+/// 比如 `EXEC` 的返回值就是一个由多个回复组成的数组.
+///
+/// `Map` 及其后面的类型是 RESP3 新增的, 只有在链接通过 `HELLO 3` 切换到 RESP3 之后才会被发送给对方,
+/// 详见 [RESP3 规范](https://github.com/antirez/RESP3/blob/master/spec.md).
+#[derive(Clone, PartialEq)]
+pub enum Frame {
     Simple(String),
     Error(String),
-    Integer(u64),
+    // 整数回复可以是负数, 比如 `TTL` 在 key 不存在时返回 -2
+    Integer(i64),
     Bulk(Bytes),
+    // RESP2 中用 `$-1` 或 `*-1` 表示, RESP3 中用 `_` 表示
     Null,
-    Array(Vec<Bytes>),
-}
\ No newline at end of file
+    Array(Vec<Frame>),
+    // 按顺序保存的键值对, 一个 key 可以是任意的帧
+    Map(Vec<(Frame, Frame)>),
+    Set(Vec<Frame>),
+    Double(f64),
+    Boolean(bool),
+    // 超出 64 位整数范围的大数, 按十进制字符串保存
+    BigNumber(String),
+    // 带格式的字符串, format 是三个字符的格式说明, 比如 `txt` 或 `mkd`
+    Verbatim { format: String, data: Bytes },
+    // 服务端主动推送的数据, 比如 pub/sub 的消息. 它和普通的回复不同, 不对应任何请求
+    Push(Vec<Frame>),
+    // 附加在回复上的属性, `data` 是真正的回复
+    Attribute { attrs: Vec<(Frame, Frame)>, data: Box<Frame> },
+}
+
+/// 解析帧时的大小限制
+///
+/// 在 `check` 阶段, 帧头中声明的长度一旦超过限制就立即返回错误, 而不是等待对方把数据发完,
+/// 这样一个声明了 `$999999999` 的客户端无法让服务端无限制地增长缓冲区. 默认值与 redis 的默认配置一致.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    /// 单个 bulk 字符串的最大字节数, 对应 redis 的 `proto-max-bulk-len`
+    pub max_bulk_len: usize,
+    /// 单个数组 (或集合, map) 的最大元素个数
+    pub max_array_len: usize,
+    /// 数组的最大嵌套层数
+    pub max_depth: usize,
+    /// 读缓冲区中等待解析的最大字节数, 对应 redis 的 `client-query-buffer-limit`
+    pub max_buffer: usize,
+    /// inline 命令一行的最大字节数, 对应 redis 的 `PROTO_INLINE_MAX_SIZE`
+    pub max_inline_len: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_bulk_len: 512 * 1024 * 1024,
+            max_array_len: 1024 * 1024,
+            max_depth: 128,
+            max_buffer: 1024 * 1024 * 1024,
+            max_inline_len: 64 * 1024,
+        }
+    }
+}
+
+/// 解析帧时可能出现的错误
+#[derive(Debug)]
+pub enum Error {
+    /// 缓冲区中还没有足够的数据来解析一个完整的帧
+    Incomplete,
+
+    /// 无效的帧编码
+    Other(super::Error),
+}
+
+impl Frame {
+    /// 返回一个空数组帧, 通常配合 `push_bulk` 与 `push_int` 来构建一个命令或回复
+    pub fn array() -> Frame {
+        Frame::Array(vec![])
+    }
+
+    /// 返回一个空的 push 帧, 用于构建 pub/sub 等服务端主动推送的消息
+    pub fn push() -> Frame {
+        Frame::Push(vec![])
+    }
+
+    /// 向数组中追加一个 bulk 帧, `self` 必须是一个数组帧 (`Array`, `Set` 或 `Push`)
+    ///
+    /// # Panics
+    ///
+    /// 如果 `self` 不是数组帧则 panic
+    pub fn push_bulk(&mut self, bytes: Bytes) {
+        match self {
+            Frame::Array(vec) | Frame::Set(vec) | Frame::Push(vec) => vec.push(Frame::Bulk(bytes)),
+            _ => panic!("not an array frame"),
+        }
+    }
+
+    /// 向数组中追加一个整数帧, `self` 必须是一个数组帧 (`Array`, `Set` 或 `Push`)
+    ///
+    /// # Panics
+    ///
+    /// 如果 `self` 不是数组帧则 panic
+    pub fn push_int(&mut self, value: i64) {
+        match self {
+            Frame::Array(vec) | Frame::Set(vec) | Frame::Push(vec) => vec.push(Frame::Integer(value)),
+            _ => panic!("not an array frame"),
+        }
+    }
+
+    /// 是否是服务端主动推送的帧. 在 RESP3 中 push 帧可能插在普通回复之间, 客户端需要用它来区分
+    pub fn is_push(&self) -> bool {
+        matches!(self, Frame::Push(_))
+    }
+
+    /// 检查 `src` 中是否包含一个完整的帧. 检查过程中 `src` 的游标会前进, 检查成功时游标指向帧的末尾.
+    ///
+    /// 使用默认的 `Limits`, 需要其它限制时使用 `check_with_limits`.
+    pub fn check(src: &mut Cursor<&[u8]>) -> Result<(), Error> {
+        Frame::check_with_limits(src, &Limits::default())
+    }
+
+    /// 按指定的 `limits` 检查 `src` 中是否包含一个完整的帧, 超过限制时返回 `Error::Other`
+    ///
+    /// 不以 RESP 类型字节开头的数据被当作 inline 命令 (比如在 telnet 中直接输入 `SET key "hello world"`),
+    /// 一直检查到换行符为止. 注意 redis 只把 `*` 开头的请求当作 RESP, 其它都是 inline 命令, 而这里的帧也用于解析
+    /// 回复, 所以任何 RESP 类型字节开头的数据都按 RESP 解析, 比如 inline 命令 `+foo` 会被解析为一个简单字符串.
+    pub fn check_with_limits(src: &mut Cursor<&[u8]>, limits: &Limits) -> Result<(), Error> {
+        if is_inline(src)? {
+            return check_inline(src, limits);
+        }
+        check_frame(src, limits, 0)
+    }
+
+    /// 解析一个帧, 调用前帧已经被 `check` 检查过了. bulk 数据会被复制到新分配的 `Bytes` 中.
+    ///
+    /// inline 命令被解析为由 bulk 字符串组成的数组, 与客户端按 RESP 发送的命令没有区别.
+    pub fn parse(src: &mut Cursor<&[u8]>) -> Result<Frame, Error> {
+        if is_inline(src)? {
+            return parse_inline(src);
+        }
+        parse_frame(src, None)
+    }
+
+    /// 从 `src` 的开头解析一个帧, 调用前帧已经被 `check` 检查过了.
+    ///
+    /// 与 `parse` 不同, bulk 数据不会被复制, 而是 `src` 的切片 (`Bytes::slice`), 与 `src` 共享同一块引用计数的内存.
+    /// 注意只要还有一个切片存活, 整块内存就不会被释放.
+    pub fn parse_shared(src: &Bytes) -> Result<Frame, Error> {
+        let mut cursor = Cursor::new(&src[..]);
+        if is_inline(&mut cursor)? {
+            // inline 命令的参数可能包含转义字符, 无法直接切片
+            return parse_inline(&mut cursor);
+        }
+        parse_frame(&mut cursor, Some(src))
+    }
+
+    /// 流式读取时检查 `src` 开头的帧是否以一个长度不小于 `threshold` 的 bulk 结尾
+    ///
+    /// 帧可以是一个 bulk, 或者最后一个元素是 bulk 的数组 (比如 `SET key value`). 是则返回 bulk 的长度,
+    /// 此时游标指向 bulk 数据的开头, bulk 之前的元素都已经被完整地检查过了. 否则返回 `None`, 调用者应按普通帧读取.
+    pub fn check_bulk_head(src: &mut Cursor<&[u8]>, threshold: usize, limits: &Limits) -> Result<Option<usize>, Error> {
+        match get_u8(src)? {
+            b'$' => check_bulk_len(src, threshold, limits),
+            b'*' => {
+                if b'-' == peek_u8(src)? {
+                    return Ok(None);
+                }
+
+                let len = check_len(src, limits, 0)?;
+                if len == 0 {
+                    return Ok(None);
+                }
+
+                // 最后一个元素之前的元素必须完整地收到
+                for _ in 1..len {
+                    check_frame(src, limits, 1)?;
+                }
+
+                match get_u8(src)? {
+                    b'$' => check_bulk_len(src, threshold, limits),
+                    _ => Ok(None),
+                }
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// 解析被 `check_bulk_head` 检查过的头部 (不包含 bulk 的数据), 返回 bulk 之前的元素
+    pub fn parse_bulk_head(src: &Bytes) -> Result<Vec<Frame>, Error> {
+        let mut cursor = Cursor::new(&src[..]);
+        let mut prefix = vec![];
+
+        if get_u8(&mut cursor)? == b'*' {
+            let len = get_decimal(&mut cursor)?;
+
+            for _ in 1..len {
+                prefix.push(parse_frame(&mut cursor, Some(src))?);
+            }
+
+            if get_u8(&mut cursor)? != b'$' {
+                return Err("protocol error; invalid frame format".into());
+            }
+        }
+
+        // 跳过 bulk 的长度
+        get_line(&mut cursor)?;
+
+        Ok(prefix)
+    }
+
+    /// 把帧转换为一个 "unexpected frame" 错误, 在命令解析遇到意外的帧类型时使用
+    pub fn to_error(&self) -> super::Error {
+        format!("unexpected frame: {}", self).into()
+    }
+
+    // 按 redis-cli 的格式输出, 嵌套数组的元素需要按 `indent` 缩进对齐
+    fn fmt_indented(&self, fmt: &mut fmt::Formatter, indent: usize) -> fmt::Result {
+        match self {
+            Frame::Simple(response) => write!(fmt, "{}", response),
+            Frame::Error(msg) => write!(fmt, "(error) {}", msg),
+            Frame::Integer(num) => write!(fmt, "(integer) {}", num),
+            Frame::Bulk(msg) => write!(fmt, "\"{}\"", msg[..].escape_ascii()),
+            Frame::Null => write!(fmt, "(nil)"),
+            Frame::Double(num) => write!(fmt, "(double) {}", num),
+            Frame::Boolean(b) => write!(fmt, "({})", b),
+            Frame::BigNumber(num) => write!(fmt, "(big number) {}", num),
+            Frame::Verbatim { format, data } => write!(fmt, "\"{}:{}\"", format, data[..].escape_ascii()),
+            Frame::Array(parts) | Frame::Push(parts) if parts.is_empty() => write!(fmt, "(empty array)"),
+            Frame::Set(parts) if parts.is_empty() => write!(fmt, "(empty set)"),
+            Frame::Map(pairs) if pairs.is_empty() => write!(fmt, "(empty hash)"),
+            Frame::Array(parts) | Frame::Push(parts) => fmt_elements(parts, ")", fmt, indent),
+            Frame::Set(parts) => fmt_elements(parts, "~", fmt, indent),
+            Frame::Map(pairs) => fmt_pairs(pairs, "#", fmt, indent),
+            Frame::Attribute { attrs, data } => {
+                fmt_pairs(attrs, "|", fmt, indent)?;
+                writeln!(fmt)?;
+                write!(fmt, "{:indent$}", "", indent = indent)?;
+                data.fmt_indented(fmt, indent)
+            }
+        }
+    }
+}
+
+/// 按 redis-cli 的风格输出帧, 比如 `(integer) 1`, `"value"`, `(nil)`, 数组的元素按 `1) ...` 的格式逐行输出
+impl fmt::Display for Frame {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_indented(fmt, 0)
+    }
+}
+
+/// 与 `Display` 相同, 按 redis-cli 的风格输出. 日志与 `assert_eq!` 中的帧和 redis-cli 中看到的回复一致,
+/// bulk 带引号而 simple 不带, 错误, 整数与浮点数都有类型前缀. 与 redis-cli 一样, push 与数组的输出相同
+impl fmt::Debug for Frame {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_indented(fmt, 0)
+    }
+}
+
+impl PartialEq<&str> for Frame {
+    fn eq(&self, other: &&str) -> bool {
+        match self {
+            Frame::Simple(s) => s.eq(other),
+            Frame::Bulk(s) => s.eq(other.as_bytes()),
+            Frame::Verbatim { data, .. } => data.eq(other.as_bytes()),
+            _ => false,
+        }
+    }
+}
+
+impl PartialEq<Bytes> for Frame {
+    fn eq(&self, other: &Bytes) -> bool {
+        match self {
+            Frame::Simple(s) => s.as_bytes() == &other[..],
+            Frame::Bulk(s) => s == other,
+            Frame::Verbatim { data, .. } => data == other,
+            _ => false,
+        }
+    }
+}
+
+// 按 `1) ...` 的格式逐行输出聚合类型的元素, 元素前的序号按最大序号的宽度右对齐
+fn fmt_elements(parts: &[Frame], mark: &str, fmt: &mut fmt::Formatter, indent: usize) -> fmt::Result {
+    let width = parts.len().to_string().len();
+
+    for (i, part) in parts.iter().enumerate() {
+        if i > 0 {
+            writeln!(fmt)?;
+            write!(fmt, "{:indent$}", "", indent = indent)?;
+        }
+        write!(fmt, "{:>width$}{} ", i + 1, mark, width = width)?;
+        part.fmt_indented(fmt, indent + width + mark.len() + 1)?;
+    }
+    Ok(())
+}
+
+// 按 `1# key => value` 的格式逐行输出键值对
+fn fmt_pairs(pairs: &[(Frame, Frame)], mark: &str, fmt: &mut fmt::Formatter, indent: usize) -> fmt::Result {
+    let width = pairs.len().to_string().len();
+
+    for (i, (key, value)) in pairs.iter().enumerate() {
+        if i > 0 {
+            writeln!(fmt)?;
+            write!(fmt, "{:indent$}", "", indent = indent)?;
+        }
+        write!(fmt, "{:>width$}{} ", i + 1, mark, width = width)?;
+        key.fmt_indented(fmt, indent + width + mark.len() + 1)?;
+        write!(fmt, " => ")?;
+        value.fmt_indented(fmt, indent + width + mark.len() + 1)?;
+    }
+    Ok(())
+}
+
+// 解析一个帧. `shared` 是 `src` 所引用的整块数据, 存在时 bulk 数据按切片返回而不复制
+fn parse_frame(src: &mut Cursor<&[u8]>, shared: Option<&Bytes>) -> Result<Frame, Error> {
+    match get_u8(src)? {
+        b'+' => {
+            // 读取一行并转换为 String
+            let line = get_line(src)?.to_vec();
+            let string = String::from_utf8(line)?;
+
+            Ok(Frame::Simple(string))
+        }
+        b'-' => {
+            let line = get_line(src)?.to_vec();
+            let string = String::from_utf8(line)?;
+
+            Ok(Frame::Error(string))
+        }
+        b':' => {
+            let value = get_integer(src)?;
+            Ok(Frame::Integer(value))
+        }
+        b',' => {
+            let value = get_double(src)?;
+            Ok(Frame::Double(value))
+        }
+        b'#' => {
+            let value = get_boolean(src)?;
+            Ok(Frame::Boolean(value))
+        }
+        b'(' => {
+            let line = get_line(src)?.to_vec();
+            let string = String::from_utf8(line)?;
+
+            // 大数只能由数字和一个可选的负号组成
+            let digits = string.strip_prefix('-').unwrap_or(&string);
+            if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+                return Err("protocol error; invalid frame format".into());
+            }
+
+            Ok(Frame::BigNumber(string))
+        }
+        b'_' => {
+            if !get_line(src)?.is_empty() {
+                return Err("protocol error; invalid frame format".into());
+            }
+            Ok(Frame::Null)
+        }
+        b'$' => {
+            if b'-' == peek_u8(src)? {
+                parse_null(src)
+            } else {
+                let data = get_blob(src, shared)?;
+                Ok(Frame::Bulk(data))
+            }
+        }
+        b'=' => {
+            let data = get_blob(src, shared)?;
+
+            // 格式为 `xxx:` 开头, 后面才是真正的数据
+            if data.len() < 4 || data[3] != b':' {
+                return Err("protocol error; invalid frame format".into());
+            }
+            let format = String::from_utf8(data[..3].to_vec())?;
+
+            Ok(Frame::Verbatim { format, data: data.slice(4..) })
+        }
+        b'*' => {
+            if b'-' == peek_u8(src)? {
+                return parse_null(src);
+            }
+            Ok(Frame::Array(parse_elements(src, shared)?))
+        }
+        b'~' => Ok(Frame::Set(parse_elements(src, shared)?)),
+        b'>' => Ok(Frame::Push(parse_elements(src, shared)?)),
+        b'%' => Ok(Frame::Map(parse_pairs(src, shared)?)),
+        b'|' => {
+            let attrs = parse_pairs(src, shared)?;
+            let data = Box::new(parse_frame(src, shared)?);
+
+            Ok(Frame::Attribute { attrs, data })
+        }
+        actual => Err(format!("protocol error; invalid frame type byte `{}`", actual).into()),
+    }
+}
+
+// 检查一个帧, `depth` 是当前帧所在的嵌套层数
+fn check_frame(src: &mut Cursor<&[u8]>, limits: &Limits, depth: usize) -> Result<(), Error> {
+    match get_u8(src)? {
+        b'+' | b'-' | b'(' => {
+            get_line(src)?;
+            Ok(())
+        }
+        b':' => {
+            let _ = get_integer(src)?;
+            Ok(())
+        }
+        b',' => {
+            let _ = get_double(src)?;
+            Ok(())
+        }
+        b'#' => {
+            let _ = get_boolean(src)?;
+            Ok(())
+        }
+        b'_' => skip(src, 2),
+        b'$' | b'=' => {
+            if b'-' == peek_u8(src)? {
+                // 跳过 '-1\r\n'
+                skip(src, 4)
+            } else {
+                let len: usize = get_decimal(src)?.try_into()?;
+                if len > limits.max_bulk_len {
+                    return Err("protocol error; invalid bulk length".into());
+                }
+                // 跳过数据长度 + 2 (\r\n) 个字节
+                skip(src, with_crlf(len)?)
+            }
+        }
+        b'*' | b'~' | b'>' => {
+            if b'-' == peek_u8(src)? {
+                // null 数组 '*-1\r\n'
+                return skip(src, 4);
+            }
+
+            let len = check_len(src, limits, depth)?;
+
+            // 数组中的元素可以是任意的帧, 递归检查
+            for _ in 0..len {
+                check_frame(src, limits, depth + 1)?;
+            }
+            Ok(())
+        }
+        b'%' => check_pairs(src, limits, depth),
+        b'|' => {
+            check_pairs(src, limits, depth)?;
+            // 属性后面紧跟着真正的回复, 回复也计入嵌套层数, 避免无限串联的属性耗尽栈空间
+            check_frame(src, limits, depth + 1)
+        }
+        actual => Err(format!("protocol error; invalid frame type byte `{}`", actual).into()),
+    }
+}
+
+// 读取 `$` 之后的长度, 长度不小于 `threshold` 时返回它, null 或较短的 bulk 返回 None
+fn check_bulk_len(src: &mut Cursor<&[u8]>, threshold: usize, limits: &Limits) -> Result<Option<usize>, Error> {
+    if b'-' == peek_u8(src)? {
+        return Ok(None);
+    }
+
+    let len: usize = get_decimal(src)?.try_into()?;
+    if len > limits.max_bulk_len {
+        return Err("protocol error; invalid bulk length".into());
+    }
+    // 读取主体时还要消费结尾的 \r\n
+    with_crlf(len)?;
+
+    Ok(if len >= threshold { Some(len) } else { None })
+}
+
+// 读取聚合类型的元素个数, 并检查元素个数与嵌套层数的限制
+fn check_len(src: &mut Cursor<&[u8]>, limits: &Limits, depth: usize) -> Result<u64, Error> {
+    let len = get_decimal(src)?;
+
+    if len > limits.max_array_len as u64 {
+        return Err("protocol error; invalid multibulk length".into());
+    }
+    if depth >= limits.max_depth {
+        return Err("protocol error; too many nested aggregates".into());
+    }
+
+    Ok(len)
+}
+
+// 检查 `%` 或 `|` 之后的键值对
+fn check_pairs(src: &mut Cursor<&[u8]>, limits: &Limits, depth: usize) -> Result<(), Error> {
+    let len = check_len(src, limits, depth)?;
+
+    // 每一个键值对由两个帧组成
+    for _ in 0..len {
+        check_frame(src, limits, depth + 1)?;
+        check_frame(src, limits, depth + 1)?;
+    }
+    Ok(())
+}
+
+// 解析聚合类型 (数组, 集合, push) 中的元素, 元素可以是任意的帧
+fn parse_elements(src: &mut Cursor<&[u8]>, shared: Option<&Bytes>) -> Result<Vec<Frame>, Error> {
+    let len = get_decimal(src)?.try_into()?;
+    let mut out = Vec::with_capacity(len);
+
+    for _ in 0..len {
+        out.push(parse_frame(src, shared)?);
+    }
+
+    Ok(out)
+}
+
+// 解析 map 或属性中的键值对
+fn parse_pairs(src: &mut Cursor<&[u8]>, shared: Option<&Bytes>) -> Result<Vec<(Frame, Frame)>, Error> {
+    let len = get_decimal(src)?.try_into()?;
+    let mut out = Vec::with_capacity(len);
+
+    for _ in 0..len {
+        let key = parse_frame(src, shared)?;
+        let value = parse_frame(src, shared)?;
+        out.push((key, value));
+    }
+
+    Ok(out)
+}
+
+// 读取 `$` 或 `=` 之后带长度前缀的数据, `shared` 存在时返回它的切片, 否则复制数据
+fn get_blob(src: &mut Cursor<&[u8]>, shared: Option<&Bytes>) -> Result<Bytes, Error> {
+    let len = get_decimal(src)?.try_into()?;
+    let n = with_crlf(len)?;
+
+    if src.remaining() < n {
+        return Err(Error::Incomplete);
+    }
+
+    let start = src.position() as usize;
+    let data = match shared {
+        Some(shared) => shared.slice(start..start + len),
+        None => Bytes::copy_from_slice(&src.bytes()[..len]),
+    };
+
+    // 数据后面必须紧跟 \r\n
+    if &src.bytes()[len..n] != b"\r\n" {
+        return Err("protocol error; invalid frame format".into());
+    }
+    skip(src, n)?;
+
+    Ok(data)
+}
+
+// 解析 `$-1\r\n` 或 `*-1\r\n` 表示的 null
+fn parse_null(src: &mut Cursor<&[u8]>) -> Result<Frame, Error> {
+    let line = get_line(src)?;
+
+    if line != b"-1" {
+        return Err("protocol error; invalid frame format".into());
+    }
+
+    Ok(Frame::Null)
+}
+
+// 开头的字节不是任何 RESP 类型时, 按 inline 命令处理. 只有最外层的帧才可能是 inline 命令.
+// 与 redis 只检查 `*` 不同, 见 `Frame::check_with_limits`
+fn is_inline(src: &mut Cursor<&[u8]>) -> Result<bool, Error> {
+    let inline = !matches!(
+        peek_u8(src)?,
+        b'+' | b'-' | b':' | b'$' | b'*' | b'_' | b',' | b'#' | b'(' | b'=' | b'%' | b'~' | b'>' | b'|'
+    );
+    Ok(inline)
+}
+
+fn check_inline(src: &mut Cursor<&[u8]>, limits: &Limits) -> Result<(), Error> {
+    let start = src.position() as usize;
+    match get_inline_line(src) {
+        Err(Error::Incomplete) if src.get_ref().len() - start > limits.max_inline_len => {
+            Err("protocol error; too big inline request".into())
+        }
+        Err(err) => Err(err),
+        Ok(line) if line.len() > limits.max_inline_len => Err("protocol error; too big inline request".into()),
+        Ok(line) => {
+            // 提前检查引号是否匹配, 这样 parse 时不会失败
+            split_args(line)?;
+            Ok(())
+        }
+    }
+}
+
+fn parse_inline(src: &mut Cursor<&[u8]>) -> Result<Frame, Error> {
+    let args = split_args(get_inline_line(src)?)?;
+    Ok(Frame::Array(args.into_iter().map(Frame::Bulk).collect()))
+}
+
+// inline 命令以 \n 结尾, telnet 发送的 \r\n 中的 \r 被去掉
+fn get_inline_line<'a>(src: &mut Cursor<&'a [u8]>) -> Result<&'a [u8], Error> {
+    let start = src.position() as usize;
+    let buf: &'a [u8] = src.get_ref();
+
+    match buf[start..].iter().position(|&b| b == b'\n') {
+        Some(i) => {
+            src.set_position((start + i + 1) as u64);
+            let line = &buf[start..start + i];
+            Ok(line.strip_suffix(b"\r").unwrap_or(line))
+        }
+        None => Err(Error::Incomplete),
+    }
+}
+
+// 按空白字符把一行分割为参数, 与 redis 的 `sdssplitargs` 规则相同:
+// 双引号中支持 \n \r \t \b \a \\ \" 与 \xHH 转义, 单引号中只支持 \' 转义, 结束的引号后面必须是空白或行尾.
+fn split_args(line: &[u8]) -> Result<Vec<Bytes>, Error> {
+    let unbalanced = || Error::from("protocol error; unbalanced quotes in request");
+    let mut args = vec![];
+    let mut i = 0;
+
+    loop {
+        // 跳过参数之间的空白
+        while i < line.len() && line[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        if i == line.len() {
+            return Ok(args);
+        }
+
+        let mut current = vec![];
+        let mut in_dq = false;
+        let mut in_sq = false;
+
+        loop {
+            let c = line.get(i).copied();
+            if in_dq {
+                match c {
+                    None => return Err(unbalanced()),
+                    Some(b'\\') if i + 3 < line.len() && line[i + 1] == b'x' && is_hex_pair(&line[i + 2..i + 4]) => {
+                        current.push(hex_value(line[i + 2]) * 16 + hex_value(line[i + 3]));
+                        i += 4;
+                    }
+                    Some(b'\\') if i + 1 < line.len() => {
+                        current.push(match line[i + 1] {
+                            b'n' => b'\n',
+                            b'r' => b'\r',
+                            b't' => b'\t',
+                            b'b' => 0x08,
+                            b'a' => 0x07,
+                            other => other,
+                        });
+                        i += 2;
+                    }
+                    Some(b'"') => {
+                        if line.get(i + 1).is_some_and(|b| !b.is_ascii_whitespace()) {
+                            return Err(unbalanced());
+                        }
+                        i += 1;
+                        break;
+                    }
+                    Some(b) => {
+                        current.push(b);
+                        i += 1;
+                    }
+                }
+            } else if in_sq {
+                match c {
+                    None => return Err(unbalanced()),
+                    Some(b'\\') if line.get(i + 1) == Some(&b'\'') => {
+                        current.push(b'\'');
+                        i += 2;
+                    }
+                    Some(b'\'') => {
+                        if line.get(i + 1).is_some_and(|b| !b.is_ascii_whitespace()) {
+                            return Err(unbalanced());
+                        }
+                        i += 1;
+                        break;
+                    }
+                    Some(b) => {
+                        current.push(b);
+                        i += 1;
+                    }
+                }
+            } else {
+                match c {
+                    None => break,
+                    Some(b) if b.is_ascii_whitespace() => break,
+                    Some(b'"') => in_dq = true,
+                    Some(b'\'') => in_sq = true,
+                    Some(b) => current.push(b),
+                }
+                i += 1;
+            }
+        }
+
+        args.push(Bytes::from(current));
+    }
+}
+
+fn is_hex_pair(src: &[u8]) -> bool {
+    src.iter().all(u8::is_ascii_hexdigit)
+}
+
+fn hex_value(b: u8) -> u8 {
+    (b as char).to_digit(16).unwrap() as u8
+}
+
+fn peek_u8(src: &mut Cursor<&[u8]>) -> Result<u8, Error> {
+    if !src.has_remaining() {
+        return Err(Error::Incomplete);
+    }
+
+    Ok(src.bytes()[0])
+}
+
+fn get_u8(src: &mut Cursor<&[u8]>) -> Result<u8, Error> {
+    if !src.has_remaining() {
+        return Err(Error::Incomplete);
+    }
+
+    Ok(src.get_u8())
+}
+
+// bulk 数据的长度加上结尾的 \r\n. 长度由对方发送, `max_bulk_len` 接近 usize::MAX 时可能溢出
+fn with_crlf(len: usize) -> Result<usize, Error> {
+    len.checked_add(2).ok_or_else(|| "protocol error; invalid bulk length".into())
+}
+
+fn skip(src: &mut Cursor<&[u8]>, n: usize) -> Result<(), Error> {
+    if src.remaining() < n {
+        return Err(Error::Incomplete);
+    }
+
+    src.advance(n);
+    Ok(())
+}
+
+/// 读取以 \r\n 结尾的十进制数
+fn get_decimal(src: &mut Cursor<&[u8]>) -> Result<u64, Error> {
+    let line = get_line(src)?;
+
+    std::str::from_utf8(line)
+        .ok()
+        .and_then(|s| s.parse::<u64>().ok())
+        .ok_or_else(|| "protocol error; invalid frame format".into())
+}
+
+/// 读取以 \r\n 结尾的有符号整数
+fn get_integer(src: &mut Cursor<&[u8]>) -> Result<i64, Error> {
+    let line = get_line(src)?;
+
+    std::str::from_utf8(line)
+        .ok()
+        .and_then(|s| s.parse::<i64>().ok())
+        .ok_or_else(|| "protocol error; invalid frame format".into())
+}
+
+/// 读取以 \r\n 结尾的浮点数, 除了普通的小数外还可能是 `inf`, `-inf` 或 `nan`
+fn get_double(src: &mut Cursor<&[u8]>) -> Result<f64, Error> {
+    let line = get_line(src)?;
+
+    std::str::from_utf8(line)
+        .ok()
+        .and_then(|s| s.parse::<f64>().ok())
+        .ok_or_else(|| "protocol error; invalid frame format".into())
+}
+
+/// 读取 `t` 或 `f` 表示的布尔值
+fn get_boolean(src: &mut Cursor<&[u8]>) -> Result<bool, Error> {
+    match get_line(src)? {
+        b"t" => Ok(true),
+        b"f" => Ok(false),
+        _ => Err("protocol error; invalid frame format".into()),
+    }
+}
+
+/// 查找一行, 返回的切片不包含 \r\n
+fn get_line<'a>(src: &mut Cursor<&'a [u8]>) -> Result<&'a [u8], Error> {
+    let start = src.position() as usize;
+    let buf: &'a [u8] = src.get_ref();
+
+    // 扫描到倒数第二个字节
+    if let Some(i) = (start..buf.len().saturating_sub(1)).find(|&i| buf[i] == b'\r' && buf[i + 1] == b'\n') {
+        // 找到一行, 游标移动到 \n 之后
+        src.set_position((i + 2) as u64);
+        return Ok(&buf[start..i]);
+    }
+
+    Err(Error::Incomplete)
+}
+
+impl From<String> for Error {
+    fn from(src: String) -> Error {
+        Error::Other(src.into())
+    }
+}
+
+impl From<&str> for Error {
+    fn from(src: &str) -> Error {
+        src.to_string().into()
+    }
+}
+
+impl From<FromUtf8Error> for Error {
+    fn from(_src: FromUtf8Error) -> Error {
+        "protocol error; invalid frame format".into()
+    }
+}
+
+impl From<TryFromIntError> for Error {
+    fn from(_src: TryFromIntError) -> Error {
+        "protocol error; invalid frame format".into()
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Incomplete => "stream ended early".fmt(fmt),
+            Error::Other(err) => err.fmt(fmt),
+        }
+    }
+}
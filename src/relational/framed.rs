@@ -0,0 +1,153 @@
+//! 把一个字节流和一个编解码器组合成帧的读写.
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use bytes::{Buf, BytesMut};
+use futures::future::poll_fn;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use super::Result;
+use super::codec::{Decoder, Encoder};
+use super::write_buf::WriteBuf;
+
+/// 基于 `AsyncRead + AsyncWrite` 流的帧读写
+///
+/// `Framed` 负责缓冲区的管理: 读取时不断从流中读取数据到读缓冲区, 直到编解码器能解码出一个完整的帧;
+/// 写入时先把帧编码到写缓冲区, 再通过一次向量化的写把缓冲区写到流中. 流可以是 `TcpStream`, `UnixStream`, `tokio::io::duplex`
+/// 或者 TLS 流, 编解码器决定了具体的协议.
+#[derive(Debug)]
+pub struct Framed<T, C> {
+    stream: T,
+    codec: C,
+    read_buf: BytesMut,
+    write_buf: WriteBuf,
+}
+
+impl<T, C> Framed<T, C>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn new(stream: T, codec: C) -> Framed<T, C> {
+        Framed {
+            stream,
+            codec,
+            // 默认分配4kb容量给读缓冲区
+            read_buf: BytesMut::with_capacity(4 * 1024),
+            write_buf: WriteBuf::new(),
+        }
+    }
+
+    /// 从流中读取一个帧，如果EOF到就返回 None
+    pub async fn read_frame(&mut self) -> Result<Option<C::Item>>
+    where
+        C: Decoder,
+    {
+        loop {
+            // 尝试从缓冲区中解析一个帧，如果buffer中有足够的数据那么帧就返回
+            if let Some(frame) = self.codec.decode(&mut self.read_buf)? {
+                return Ok(Some(frame));
+            }
+
+            // 如果没有足够的数据读取到一个帧中,那么尝试从流中读取更多的数据
+            // 如果成功了，一定数量的字节被返回, 0 表时到了流的末尾了.
+            if 0 == self.read_more().await? {
+                // 给编解码器一个机会处理剩余的数据, 比如最后一行没有换行符
+                let frame = self.codec.decode_eof(&mut self.read_buf)?;
+
+                // 远程关闭了链接, 为了彻底关闭,读缓冲区中应该没有数据了,如果还存在数据那说明对等方在发送帧时关闭了链接
+                return match frame {
+                    Some(frame) => Ok(Some(frame)),
+                    None if self.read_buf.is_empty() => Ok(None),
+                    None => Err("connection reset by peer".into()),
+                };
+            }
+        }
+    }
+
+    /// 只从读缓冲区中解码一个帧, 不会从流中读取数据. 缓冲区中没有完整的帧时返回 None
+    pub fn try_read_frame(&mut self) -> Result<Option<C::Item>>
+    where
+        C: Decoder,
+    {
+        self.codec.decode(&mut self.read_buf)
+    }
+
+    /// 写一个帧到流中, 帧被编码到写缓冲区后 flush 一次
+    pub async fn write_frame<I>(&mut self, item: I) -> Result<()>
+    where
+        C: Encoder<I>,
+    {
+        self.feed(item)?;
+        self.flush().await
+    }
+
+    /// 把一个帧编码到写缓冲区中, 不会写到流中. 多个帧可以先放入缓冲区, 再通过 `flush` 一次写出
+    pub fn feed<I>(&mut self, item: I) -> Result<()>
+    where
+        C: Encoder<I>,
+    {
+        self.codec.encode(item, &mut self.write_buf)
+    }
+
+    /// 把写缓冲区中的所有数据写到流中
+    pub async fn flush(&mut self) -> Result<()> {
+        while self.write_buf.has_remaining() {
+            // write_buf 使用 writev 一次写出缓冲区中的所有块
+            if 0 == self.stream.write_buf(&mut self.write_buf).await? {
+                return Err(io::Error::from(io::ErrorKind::WriteZero).into());
+            }
+        }
+        self.stream.flush().await?;
+
+        Ok(())
+    }
+
+    /// 从流中读取更多的数据追加到读缓冲区, 返回读取的字节数, 0 表示到了流的末尾
+    pub async fn read_more(&mut self) -> io::Result<usize> {
+        poll_fn(|cx| self.poll_read_more(cx)).await
+    }
+
+    /// `read_more` 的 poll 版本, 供手动实现的 future 或 stream 使用
+    pub fn poll_read_more(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        // 缓冲区满时 BytesMut 每次只扩容 64 字节, 这里提前预留空间, 避免读取大量数据时产生很多次小的读取
+        if self.read_buf.capacity() - self.read_buf.len() < 1024 {
+            self.read_buf.reserve(8 * 1024);
+        }
+
+        Pin::new(&mut self.stream).poll_read_buf(cx, &mut self.read_buf)
+    }
+
+    /// 返回编解码器的引用
+    pub fn codec(&self) -> &C {
+        &self.codec
+    }
+
+    /// 返回编解码器的可变引用, 可以用来修改编解码器的状态, 比如切换协议版本
+    pub fn codec_mut(&mut self) -> &mut C {
+        &mut self.codec
+    }
+
+    /// 返回底层流的引用
+    pub fn get_ref(&self) -> &T {
+        &self.stream
+    }
+
+    /// 返回底层流的可变引用. 注意直接读写底层流会绕过 `Framed` 的缓冲区
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.stream
+    }
+
+    /// 返回读缓冲区中已经读取但还没有被解码的数据
+    pub fn read_buffer(&self) -> &BytesMut {
+        &self.read_buf
+    }
+
+    /// 返回读缓冲区的可变引用, 用于绕过编解码器直接消费缓冲区中的数据
+    pub fn read_buffer_mut(&mut self) -> &mut BytesMut {
+        &mut self.read_buf
+    }
+
+    /// 消费 `Framed`, 返回底层流. 读缓冲区中还没有被解码的数据会被丢弃
+    pub fn into_inner(self) -> T {
+        self.stream
+    }
+}
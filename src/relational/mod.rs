@@ -1,2 +1,13 @@
+pub mod cmd;
+pub mod codec;
+pub mod db;
 pub mod connection;
-pub mod frame_enum;
\ No newline at end of file
+pub mod frame_enum;
+pub mod framed;
+pub mod write_buf;
+
+/// relational 模块中使用的错误类型, 大部分错误都直接转换为 boxed 的 `std::error::Error`
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// relational 模块中使用的 `Result` 类型
+pub type Result<T> = std::result::Result<T, Error>;
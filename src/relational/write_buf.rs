@@ -0,0 +1,115 @@
+//! 由多个字节块组成的写缓冲区.
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::collections::VecDeque;
+use std::io::IoSlice;
+
+// 小于这个长度的 `Bytes` 直接复制到缓冲区末尾, 单独作为一个块反而会增加 writev 的开销
+const COPY_THRESHOLD: usize = 1024;
+
+/// 编码器使用的写缓冲区
+///
+/// 帧头之类的小数据被复制到末尾的 `BytesMut` 中, 而较大的 `Bytes` (比如 bulk 的数据) 作为单独的块保存,
+/// 只增加引用计数, 不会被复制. `WriteBuf` 实现了 `Buf::bytes_vectored`, 写到流中时所有的块通过一次
+/// 向量化的写 (writev) 完成.
+#[derive(Debug, Default)]
+pub struct WriteBuf {
+    // 已经冻结的块, 按写入的顺序排列
+    chunks: VecDeque<Bytes>,
+    // 正在写入的最后一块
+    tail: BytesMut,
+}
+
+impl WriteBuf {
+    pub fn new() -> WriteBuf {
+        WriteBuf::default()
+    }
+
+    /// 缓冲区中还没有写出的字节数
+    pub fn len(&self) -> usize {
+        self.chunks.iter().map(Bytes::len).sum::<usize>() + self.tail.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty() && self.tail.is_empty()
+    }
+
+    /// 为末尾的块预留至少 `additional` 字节的容量
+    pub fn reserve(&mut self, additional: usize) {
+        self.tail.reserve(additional);
+    }
+
+    pub fn put_u8(&mut self, n: u8) {
+        self.tail.put_u8(n);
+    }
+
+    /// 按大端序写入一个 u32
+    pub fn put_u32(&mut self, n: u32) {
+        self.tail.put_u32(n);
+    }
+
+    /// 复制一段数据到缓冲区末尾
+    pub fn put_slice(&mut self, src: &[u8]) {
+        self.tail.put_slice(src);
+    }
+
+    /// 追加一段 `Bytes`, 较大的数据作为单独的块保存而不复制
+    pub fn put_bytes(&mut self, src: Bytes) {
+        if src.len() < COPY_THRESHOLD {
+            self.tail.put_slice(&src);
+        } else {
+            self.freeze_tail();
+            self.chunks.push_back(src);
+        }
+    }
+
+    // 把末尾正在写入的数据冻结为一个块
+    fn freeze_tail(&mut self) {
+        if !self.tail.is_empty() {
+            self.chunks.push_back(self.tail.split().freeze());
+        }
+    }
+}
+
+impl Buf for WriteBuf {
+    fn remaining(&self) -> usize {
+        self.len()
+    }
+
+    fn bytes(&self) -> &[u8] {
+        match self.chunks.front() {
+            Some(chunk) => chunk,
+            None => &self.tail,
+        }
+    }
+
+    fn advance(&mut self, mut cnt: usize) {
+        while cnt > 0 {
+            match self.chunks.front_mut() {
+                Some(chunk) if cnt >= chunk.len() => {
+                    cnt -= chunk.len();
+                    self.chunks.pop_front();
+                }
+                Some(chunk) => {
+                    chunk.advance(cnt);
+                    return;
+                }
+                None => {
+                    self.tail.advance(cnt);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn bytes_vectored<'a>(&'a self, dst: &mut [IoSlice<'a>]) -> usize {
+        let chunks = self.chunks.iter().map(|chunk| &chunk[..]);
+        let tail = Some(&self.tail[..]).filter(|tail| !tail.is_empty());
+
+        let mut n = 0;
+        for (slot, chunk) in dst.iter_mut().zip(chunks.chain(tail)) {
+            *slot = IoSlice::new(chunk);
+            n += 1;
+        }
+        n
+    }
+}
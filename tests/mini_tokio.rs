@@ -0,0 +1,830 @@
+//! mini-tokio 的测试: 产生任务, 唤醒, 计时器与执行顺序.
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::panic;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::io::{AsyncReadExt, AsyncWriteExt};
+use bytes::Bytes;
+use futures::FutureExt;
+use tokio::sync::{mpsc, oneshot};
+
+use tokio_cn_doc::mini_tokio::net::{TcpListener, TcpStream};
+use tokio_cn_doc::mini_tokio::sim;
+use tokio_cn_doc::mini_tokio::time::{self, delay, sleep_until};
+use tokio_cn_doc::relational::cmd::Command;
+use tokio_cn_doc::relational::db::{self, Db};
+use tokio_cn_doc::relational::frame_enum::Frame;
+
+use tokio_cn_doc::mini_tokio::{panic_message, spawn, spawn_blocking, spawn_local, yield_now, LocalSet, MiniTokio, Scheduler};
+
+const SCHEDULERS: [Scheduler; 2] = [Scheduler::Channel, Scheduler::WorkStealing];
+
+fn runtime(scheduler: Scheduler, worker_threads: usize) -> MiniTokio {
+    MiniTokio::builder().worker_threads(worker_threads).scheduler(scheduler).build().unwrap()
+}
+
+// 产生的任务通过 JoinHandle 返回输出, 任务中也可以继续产生任务
+#[test]
+fn spawn_returns_output() {
+    for &scheduler in &SCHEDULERS {
+        let output = runtime(scheduler, 2).block_on(async {
+            let outer = spawn(async {
+                let inner = spawn(async { 20 });
+                inner.await.unwrap() + 1
+            });
+            outer.await.unwrap() * 2
+        });
+        assert_eq!(output, 42);
+    }
+}
+
+// 没有任务时 run 立即返回, 所有任务完成后 run 返回
+#[test]
+fn run_returns_when_all_tasks_complete() {
+    for &scheduler in &SCHEDULERS {
+        let mut mini_tokio = runtime(scheduler, 2);
+        mini_tokio.run();
+
+        let done = Arc::new(AtomicUsize::new(0));
+        for _ in 0..10 {
+            let done = done.clone();
+            mini_tokio.spawn(async move {
+                delay(Duration::from_millis(5)).await;
+                done.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        mini_tokio.run();
+        assert_eq!(done.load(Ordering::SeqCst), 10);
+    }
+}
+
+// 由其它线程唤醒的 future. 第一次 poll 时把 waker 交给一个线程, 线程稍后唤醒它, 完成时返回被 poll 的次数
+struct WakeFromThread {
+    woken: Arc<AtomicBool>,
+    polls: usize,
+}
+
+impl Future for WakeFromThread {
+    type Output = usize;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<usize> {
+        self.polls += 1;
+        if self.woken.load(Ordering::SeqCst) {
+            return Poll::Ready(self.polls);
+        }
+
+        if self.polls == 1 {
+            let (woken, waker) = (self.woken.clone(), cx.waker().clone());
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(10));
+                woken.store(true, Ordering::SeqCst);
+                waker.wake();
+            });
+        }
+        Poll::Pending
+    }
+}
+
+// 被执行器之外的线程唤醒的任务会再次被 poll
+#[test]
+fn wake_from_another_thread() {
+    for &scheduler in &SCHEDULERS {
+        let polls = runtime(scheduler, 2).block_on(async {
+            spawn(WakeFromThread { woken: Arc::new(AtomicBool::new(false)), polls: 0 }).await.unwrap()
+        });
+        assert_eq!(polls, 2);
+    }
+}
+
+// 计时器不会提前触发, 并且按到期时间的先后唤醒任务
+#[test]
+fn timers_fire_in_deadline_order() {
+    for &scheduler in &SCHEDULERS {
+        let order = Arc::new(Mutex::new(vec![]));
+        let mut mini_tokio = runtime(scheduler, 2);
+
+        let start = Instant::now();
+        for &ms in &[30u64, 10, 50, 20, 40] {
+            let order = order.clone();
+            mini_tokio.spawn(async move {
+                let when = start + Duration::from_millis(ms);
+                sleep_until(when).await;
+                assert!(Instant::now() >= when);
+                order.lock().unwrap().push(ms);
+            });
+        }
+        mini_tokio.run();
+
+        assert_eq!(*order.lock().unwrap(), [10, 20, 30, 40, 50]);
+    }
+}
+
+// 被取消的任务的计时器从时间轮中注销, 不会让执行器等待它到期
+#[test]
+fn aborted_timer_does_not_block() {
+    for &scheduler in &SCHEDULERS {
+        let start = Instant::now();
+        runtime(scheduler, 1).block_on(async {
+            let task = spawn(delay(Duration::from_secs(3600)));
+            delay(Duration::from_millis(10)).await;
+            task.abort();
+            assert!(task.await.unwrap_err().is_cancelled());
+        });
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+}
+
+// 只有一个 worker 时, 在执行器之外产生的任务按产生的顺序执行
+#[test]
+fn tasks_run_in_spawn_order() {
+    for &scheduler in &SCHEDULERS {
+        let order = Arc::new(Mutex::new(vec![]));
+        let mut mini_tokio = runtime(scheduler, 1);
+        for i in 0..10 {
+            let order = order.clone();
+            mini_tokio.spawn(async move {
+                order.lock().unwrap().push(i);
+            });
+        }
+        mini_tokio.run();
+
+        assert_eq!(*order.lock().unwrap(), (0..10).collect::<Vec<_>>());
+    }
+}
+
+// 关闭执行器时还没有完成的任务被 drop, 等待它们的 JoinHandle 得到取消的错误
+#[test]
+fn shutdown_cancels_pending_tasks() {
+    for &scheduler in &SCHEDULERS {
+        let mut mini_tokio = runtime(scheduler, 2);
+        let shutdown = mini_tokio.shutdown_handle();
+        let handle = Arc::new(Mutex::new(None));
+
+        let slot = handle.clone();
+        mini_tokio.spawn(async move {
+            *slot.lock().unwrap() = Some(spawn(delay(Duration::from_secs(3600))));
+            delay(Duration::from_millis(10)).await;
+            shutdown.shutdown();
+        });
+        mini_tokio.run();
+
+        let task = handle.lock().unwrap().take().unwrap();
+        assert!(futures::executor::block_on(task).unwrap_err().is_cancelled());
+    }
+}
+
+// 只有一个 worker 时 block_on 的调用线程同时执行产生的任务. 执行器被关闭后 block_on 仍然等待 future 完成
+#[test]
+fn block_on_runs_tasks_on_the_calling_thread() {
+    for &scheduler in &SCHEDULERS {
+        let mini_tokio = runtime(scheduler, 1);
+        let shutdown = mini_tokio.shutdown_handle();
+
+        let caller = thread::current().id();
+        let output = mini_tokio.block_on(async move {
+            let task = spawn(async { thread::current().id() });
+            assert_eq!(task.await.unwrap(), caller);
+
+            shutdown.shutdown();
+            let (tx, rx) = oneshot::channel();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(10));
+                tx.send(42).unwrap();
+            });
+            rx.await.unwrap()
+        });
+        assert_eq!(output, 42);
+    }
+}
+
+// 通过 reactor 驱动的 TCP socket 收发数据
+#[test]
+fn tcp_echo() {
+    for &scheduler in &SCHEDULERS {
+        let echoed = runtime(scheduler, 2).block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+
+            spawn(async move {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = [0; 1024];
+                loop {
+                    match socket.read(&mut buf).await.unwrap() {
+                        0 => return,
+                        n => socket.write_all(&buf[..n]).await.unwrap(),
+                    }
+                }
+            });
+
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let data: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+            let (mut reader, mut writer) = stream.split();
+            let write = async {
+                writer.write_all(&data).await.unwrap();
+                writer.close().await.unwrap();
+            };
+            let mut echoed = vec![];
+            let read = reader.read_to_end(&mut echoed);
+            let (_, read) = futures::join!(write, read);
+            read.unwrap();
+            echoed == data
+        });
+        assert!(echoed, "{:?}", scheduler);
+    }
+}
+
+// 在一个任务中一次产生 10 万个任务再等待它们全部完成, 调度队列不能阻塞唤醒任务的 worker
+fn spawn_many(scheduler: Scheduler, worker_threads: usize) {
+    const TASKS: usize = 100_000;
+
+    let mut mini_tokio = MiniTokio::builder().worker_threads(worker_threads).scheduler(scheduler).build().unwrap();
+    let done = Arc::new(AtomicUsize::new(0));
+    let joined = Arc::new(AtomicUsize::new(0));
+
+    let (done2, joined2) = (done.clone(), joined.clone());
+    mini_tokio.spawn(async move {
+        let handles: Vec<_> = (0..TASKS)
+            .map(|_| {
+                let done = done2.clone();
+                spawn(async move {
+                    done.fetch_add(1, Ordering::Relaxed);
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.await.unwrap();
+            joined2.fetch_add(1, Ordering::Relaxed);
+        }
+    });
+    mini_tokio.run();
+
+    assert_eq!(done.load(Ordering::Relaxed), TASKS);
+    assert_eq!(joined.load(Ordering::Relaxed), TASKS);
+}
+
+#[test]
+fn spawn_100k_tasks_channel() {
+    spawn_many(Scheduler::Channel, 1);
+    spawn_many(Scheduler::Channel, 4);
+}
+
+#[test]
+fn spawn_100k_tasks_work_stealing() {
+    spawn_many(Scheduler::WorkStealing, 1);
+    spawn_many(Scheduler::WorkStealing, 4);
+}
+
+// 析构函数 panic 的值
+struct PanicOnDrop;
+
+impl Drop for PanicOnDrop {
+    fn drop(&mut self) {
+        panic!("drop");
+    }
+}
+
+// panic 的任务不影响其它任务, panic 的参数交给钩子. 析构函数中的 panic 也不会让 worker 退出
+#[test]
+fn panics_are_isolated() {
+    let panics = Arc::new(Mutex::new(vec![]));
+    let hooked = panics.clone();
+    let mut mini_tokio = MiniTokio::builder()
+        .worker_threads(2)
+        .on_task_panic(move |payload| hooked.lock().unwrap().push(panic_message(payload).unwrap().to_string()))
+        .build()
+        .unwrap();
+
+    let done = Arc::new(AtomicUsize::new(0));
+    let finished = done.clone();
+    mini_tokio.spawn(async move {
+        // 没有人等待它的 JoinHandle, 只有钩子能看到这个 panic
+        drop(spawn(async { panic!("boom") }));
+
+        let err = spawn(async { panic!("joined") }).await.unwrap_err();
+        assert_eq!(panic_message(&*err.into_panic()), Some("joined"));
+
+        let task = spawn(async {
+            let _guard = PanicOnDrop;
+            delay(Duration::from_secs(3600)).await;
+        });
+        delay(Duration::from_millis(10)).await;
+        task.abort();
+        assert!(task.await.unwrap_err().is_cancelled());
+
+        for _ in 0..100 {
+            let finished = finished.clone();
+            spawn(async move {
+                finished.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+    });
+    mini_tokio.run();
+
+    assert_eq!(done.load(Ordering::SeqCst), 100);
+    let mut panics = panics.lock().unwrap().clone();
+    panics.sort();
+    assert_eq!(panics, ["boom", "joined"]);
+}
+
+// 关闭执行器时 drop 的 future 在析构函数中 panic, panic 交给钩子, 不会从 run 或者 MiniTokio 的 drop 中传播出来
+#[test]
+fn shutdown_catches_panicking_destructors() {
+    let panics = Arc::new(Mutex::new(vec![]));
+    let hooked = panics.clone();
+    let mut mini_tokio = MiniTokio::builder()
+        .worker_threads(2)
+        .on_task_panic(move |payload| hooked.lock().unwrap().push(panic_message(payload).unwrap().to_string()))
+        .build()
+        .unwrap();
+    let shutdown = mini_tokio.shutdown_handle();
+
+    mini_tokio.spawn(async move {
+        spawn(async {
+            let _guard = PanicOnDrop;
+            delay(Duration::from_secs(3600)).await;
+        });
+        delay(Duration::from_millis(10)).await;
+        shutdown.shutdown();
+    });
+    mini_tokio.run();
+    assert_eq!(*panics.lock().unwrap(), ["drop"]);
+
+    // 从来没有被 poll 过的任务在 MiniTokio 被 drop 时 drop
+    let guard = PanicOnDrop;
+    mini_tokio.spawn(async move {
+        let _guard = guard;
+    });
+    drop(mini_tokio);
+    assert_eq!(*panics.lock().unwrap(), ["drop", "drop"]);
+}
+
+// 在 poll 的过程中被唤醒了十次的任务只会再被 poll 一次
+#[test]
+fn redundant_wakeups_poll_once() {
+    for &scheduler in &[Scheduler::Channel, Scheduler::WorkStealing] {
+        let mini_tokio = MiniTokio::builder().worker_threads(4).scheduler(scheduler).build().unwrap();
+        let polls = Arc::new(AtomicUsize::new(0));
+
+        let counter = polls.clone();
+        mini_tokio.block_on(async move {
+            let task = spawn(futures::future::poll_fn(move |cx| {
+                if counter.fetch_add(1, Ordering::SeqCst) == 0 {
+                    for _ in 0..10 {
+                        cx.waker().wake_by_ref();
+                    }
+                }
+                Poll::<()>::Pending
+            }));
+
+            delay(Duration::from_millis(50)).await;
+            // 被取消的任务直接 drop, 不会再 poll
+            task.abort();
+            assert!(task.await.unwrap_err().is_cancelled());
+        });
+
+        assert_eq!(polls.load(Ordering::SeqCst), 2, "{:?}", scheduler);
+    }
+}
+
+// 阻塞任务在线程池中执行, 不会占用唯一的 worker: 阻塞任务运行期间异步任务照常执行. 阻塞任务的 panic 通过 JoinHandle 返回
+#[test]
+fn spawn_blocking_does_not_stall_workers() {
+    for &scheduler in &SCHEDULERS {
+        runtime(scheduler, 1).block_on(async {
+            let start = Instant::now();
+            let blocking: Vec<_> = (0..4)
+                .map(|i| spawn_blocking(move || {
+                    thread::sleep(Duration::from_millis(200));
+                    i
+                }))
+                .collect();
+
+            let ticker = spawn(async move {
+                delay(Duration::from_millis(20)).await;
+                start.elapsed()
+            });
+            assert!(ticker.await.unwrap() < Duration::from_millis(200));
+
+            let mut sum = 0;
+            for handle in blocking {
+                sum += handle.await.unwrap();
+            }
+            assert_eq!(sum, 6);
+            // 4 个阻塞任务在不同的线程上并行执行
+            assert!(start.elapsed() < Duration::from_millis(600));
+
+            let err = spawn_blocking(|| panic!("blocking boom")).await.unwrap_err();
+            assert_eq!(panic_message(&*err.into_panic()), Some("blocking boom"));
+        });
+    }
+}
+
+// 阻塞线程池的线程数不超过上限, 空闲超过 keep_alive 的线程退出, 之后的任务在新的线程上执行
+#[test]
+fn blocking_pool_is_bounded_and_elastic() {
+    let mini_tokio = MiniTokio::builder()
+        .max_blocking_threads(2)
+        .thread_keep_alive(Duration::from_millis(50))
+        .build()
+        .unwrap();
+
+    let thread_name = || thread::current().name().unwrap().to_string();
+    mini_tokio.block_on(async move {
+        let start = Instant::now();
+        let handles: Vec<_> = (0..4)
+            .map(|_| spawn_blocking(move || {
+                thread::sleep(Duration::from_millis(50));
+                thread_name()
+            }))
+            .collect();
+
+        let mut names = Vec::new();
+        for handle in handles {
+            names.push(handle.await.unwrap());
+        }
+        // 只有两个线程, 4 个任务分两轮执行
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), 2, "{:?}", names);
+
+        delay(Duration::from_millis(200)).await;
+        let name = spawn_blocking(thread_name).await.unwrap();
+        assert!(!names.contains(&name), "{} should be a new thread", name);
+    });
+}
+
+// 两个忙碌的任务在一个 worker 上轮流执行, 返回每一步是哪个任务执行的
+fn interleave<F, Fut>(scheduler: Scheduler, steps: usize, step: F) -> Vec<usize>
+where F: Fn() -> Fut + Send + Sync + Copy + 'static,
+      Fut: Future<Output = ()> + Send,
+{
+    let mut mini_tokio = runtime(scheduler, 1);
+    let order = Arc::new(Mutex::new(Vec::new()));
+    for id in 0..2 {
+        let order = order.clone();
+        mini_tokio.spawn(async move {
+            for _ in 0..steps {
+                step().await;
+                order.lock().unwrap().push(id);
+            }
+        });
+    }
+    mini_tokio.run();
+
+    let order = order.lock().unwrap().clone();
+    order
+}
+
+// 不停地等待已经到期的计时器的任务用完预算后让出 worker, 两个这样的任务交替执行而不是一个执行完再执行另一个
+#[test]
+fn budget_interleaves_busy_tasks() {
+    for &scheduler in &SCHEDULERS {
+        let order = interleave(scheduler, 1000, || sleep_until(Instant::now()));
+        let switches = order.windows(2).filter(|pair| pair[0] != pair[1]).count();
+        // 每份预算 128, 2000 步至少切换 10 多次
+        assert!(switches >= 10, "{:?}: only {} switches", scheduler, switches);
+    }
+}
+
+// yield_now 让任务排到队列的末尾, 两个任务严格交替执行
+#[test]
+fn yield_now_alternates_tasks() {
+    for &scheduler in &SCHEDULERS {
+        let order = interleave(scheduler, 5, yield_now);
+        assert_eq!(order, [0, 1, 0, 1, 0, 1, 0, 1, 0, 1], "{:?}", scheduler);
+    }
+}
+
+// LocalSet 中的任务可以跨 .await 持有 Rc, 它们都在调用 block_on 的线程上执行
+#[test]
+fn local_set_runs_non_send_tasks() {
+    for &scheduler in &SCHEDULERS {
+        let mini_tokio = runtime(scheduler, 2);
+        let local = LocalSet::new();
+        let counter = Rc::new(Cell::new(0));
+
+        // 在 LocalSet 运行之前产生的任务, 运行时才开始执行
+        let early = local.spawn_local({
+            let counter = counter.clone();
+            async move {
+                yield_now().await;
+                counter.set(counter.get() + 1);
+            }
+        });
+
+        let thread = thread::current().id();
+        let output = local.block_on(&mini_tokio, async {
+            let handles: Vec<_> = (0..10)
+                .map(|i| {
+                    let counter = counter.clone();
+                    spawn_local(async move {
+                        delay(Duration::from_millis(i)).await;
+                        counter.set(counter.get() + 1);
+                        assert_eq!(thread::current().id(), thread);
+                        // 嵌套地产生任务, 输出也可以不是 Send 的
+                        spawn_local(async move { Rc::new(i) }).await.unwrap()
+                    })
+                })
+                .collect();
+
+            let mut sum = 0;
+            for handle in handles {
+                sum += *handle.await.unwrap();
+            }
+            // 在 LocalSet 中仍然可以产生普通的任务
+            sum + spawn(async { 100 }).await.unwrap()
+        });
+
+        assert_eq!(output, 145);
+        early.now_or_never().unwrap().unwrap();
+        assert_eq!(counter.get(), 11);
+    }
+}
+
+// LocalSet 中的任务可以被取消, panic 交给钩子并通过 JoinHandle 返回, drop LocalSet 时还没有完成的任务被 drop
+#[test]
+fn local_set_abort_panic_and_drop() {
+    let panics = Arc::new(Mutex::new(vec![]));
+    let hooked = panics.clone();
+    let mini_tokio = MiniTokio::builder()
+        .on_task_panic(move |payload| hooked.lock().unwrap().push(panic_message(payload).unwrap().to_string()))
+        .build()
+        .unwrap();
+    let local = LocalSet::new();
+
+    let pending = local.spawn_local(futures::future::pending::<()>());
+    local.block_on(&mini_tokio, async {
+        let aborted = spawn_local(futures::future::pending::<()>());
+        aborted.abort();
+        assert!(aborted.await.unwrap_err().is_cancelled());
+
+        let err = spawn_local(async { panic!("local boom") }).await.unwrap_err();
+        assert_eq!(panic_message(&*err.into_panic()), Some("local boom"));
+    });
+    // LocalSet 中的 panic 与执行器中的任务一样交给钩子
+    assert_eq!(*panics.lock().unwrap(), ["local boom"]);
+
+    drop(local);
+    assert!(futures::executor::block_on(pending).unwrap_err().is_cancelled());
+}
+
+tokio_cn_doc::task_local! {
+    static CONN_ID: usize;
+}
+
+// 任务局部变量的值跟随任务, 即使任务在不同的 worker 之间移动. 嵌套的 scope 暂时替换外层的值
+#[test]
+fn task_local_follows_the_task() {
+    for &scheduler in &SCHEDULERS {
+        runtime(scheduler, 4).block_on(async {
+            let handles: Vec<_> = (0..8)
+                .map(|id| spawn(CONN_ID.scope(id, async move {
+                    for i in 0..10 {
+                        delay(Duration::from_millis(1)).await;
+                        yield_now().await;
+                        assert_eq!(CONN_ID.get(), id);
+                        if i == 5 {
+                            let inner = CONN_ID.scope(id + 100, async { yield_now().await; CONN_ID.get() }).await;
+                            assert_eq!(inner, id + 100);
+                        }
+                    }
+                    CONN_ID.get()
+                })))
+                .collect();
+
+            for (id, handle) in handles.into_iter().enumerate() {
+                assert_eq!(handle.await.unwrap(), id);
+            }
+            assert!(CONN_ID.try_with(|_| ()).is_err());
+        });
+    }
+}
+
+fn simulation(seed: u64) -> MiniTokio {
+    MiniTokio::builder().simulation(seed).build().unwrap()
+}
+
+// 几个任务在虚拟时间中睡眠并让出 worker, 返回它们执行的顺序与经过的虚拟时间
+fn simulated_trace(seed: u64) -> (Vec<u64>, Duration) {
+    simulation(seed).block_on(async {
+        let start = time::now();
+        let trace = Arc::new(Mutex::new(Vec::new()));
+        let handles: Vec<_> = (0..5)
+            .map(|id| {
+                let trace = trace.clone();
+                spawn(async move {
+                    for step in 0..3 {
+                        // 有些任务在同一时间醒来, 它们的先后由种子决定
+                        delay(Duration::from_millis((id + step) % 3 * 10)).await;
+                        trace.lock().unwrap().push(id);
+                        yield_now().await;
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.await.unwrap();
+        }
+
+        // 虚拟时钟直接跳到一小时之后, 不用真的等待
+        delay(Duration::from_secs(3600)).await;
+        let trace = trace.lock().unwrap().clone();
+        (trace, time::now() - start)
+    })
+}
+
+// 同一个种子每次运行的调度顺序与时间都一样, 不同的种子产生不同的交错
+#[test]
+fn simulation_is_deterministic() {
+    let start = Instant::now();
+    let traces: Vec<_> = (0..50).map(simulated_trace).collect();
+    assert!(start.elapsed() < Duration::from_secs(10));
+
+    for (seed, trace) in traces.iter().enumerate() {
+        assert_eq!(*trace, simulated_trace(seed as u64), "seed {}", seed);
+        assert!(trace.1 >= Duration::from_secs(3600) && trace.1 < Duration::from_secs(3601), "{:?}", trace.1);
+    }
+    let distinct: HashSet<_> = traces.iter().map(|trace| &trace.0).collect();
+    assert!(distinct.len() > 10, "only {} distinct interleavings", distinct.len());
+}
+
+// 在 .await 之前读取, 之后写回的计数器, 两个任务交错执行时会丢失一次更新
+fn racy_counter(mini_tokio: &MiniTokio) -> usize {
+    mini_tokio.block_on(async {
+        let counter = Arc::new(Mutex::new(0));
+        let handles: Vec<_> = (0..2)
+            .map(|_| {
+                let counter = counter.clone();
+                spawn(async move {
+                    let value = *counter.lock().unwrap();
+                    yield_now().await;
+                    *counter.lock().unwrap() = value + 1;
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.await.unwrap();
+        }
+        let value = *counter.lock().unwrap();
+        value
+    })
+}
+
+// 模拟能找到丢失更新的交错, 用同一个种子重放时结果相同, check 在这个种子上失败
+#[test]
+fn simulation_replays_a_failing_seed() {
+    let failing = (0..100)
+        .find(|&seed| racy_counter(&simulation(seed)) != 2)
+        .expect("some interleaving should lose an update");
+    assert!((0..100).any(|seed| racy_counter(&simulation(seed)) == 2));
+    for _ in 0..10 {
+        assert_eq!(racy_counter(&simulation(failing)), 1);
+    }
+
+    let result = panic::catch_unwind(|| sim::check_seeds(0..100, |mini_tokio| assert_eq!(racy_counter(mini_tokio), 2)));
+    assert!(result.is_err());
+}
+
+// 模拟模式下所有任务都在等待, 没有计时器也没有就绪的任务时 block_on 报告死锁而不是永远挂起
+#[test]
+#[should_panic(expected = "simulation deadlocked with seed 7")]
+fn simulation_detects_deadlock() {
+    simulation(7).block_on(futures::future::pending::<()>());
+}
+
+// run 与 block_on 一样报告死锁, 而不是丢下永远不会完成的任务直接返回
+#[test]
+#[should_panic(expected = "simulation deadlocked with seed 7")]
+fn simulation_run_detects_deadlock() {
+    let mut mini_tokio = simulation(7);
+    mini_tokio.spawn(futures::future::pending::<()>());
+    mini_tokio.run();
+}
+
+// 通过 shared-state 服务端的命令执行逻辑发送一条命令
+fn request(db: &Db, args: &[&str]) -> Frame {
+    let frame = Frame::Array(args.iter().map(|arg| Frame::Bulk(Bytes::from(arg.to_string()))).collect());
+    db::execute(db, Command::from_frame(frame).unwrap())
+}
+
+// shared-state 服务端上的 "读-改-写": 几个客户端各自 GET 计数器, 加一后 SET 回去, 返回计数器的最终值.
+// `atomic` 为 false 时 GET 与 SET 之间让出一次执行权, 其它客户端可能在这期间写入, 这次写入就被覆盖了
+fn simulated_increments(mini_tokio: &MiniTokio, atomic: bool) -> u64 {
+    let db = db::new_db();
+    request(&db, &["set", "counter", "0"]);
+
+    mini_tokio.block_on(async move {
+        let clients: Vec<_> = (0..3)
+            .map(|_| {
+                let db = db.clone();
+                spawn(async move {
+                    for _ in 0..2 {
+                        let value: u64 = match request(&db, &["get", "counter"]) {
+                            Frame::Bulk(value) => std::str::from_utf8(&value).unwrap().parse().unwrap(),
+                            frame => panic!("unexpected {:?}", frame),
+                        };
+                        if !atomic {
+                            yield_now().await;
+                        }
+                        request(&db, &["set", "counter", &(value + 1).to_string()]);
+                    }
+                })
+            })
+            .collect();
+        for client in clients {
+            client.await.unwrap();
+        }
+
+        match request(&db, &["get", "counter"]) {
+            Frame::Bulk(value) => std::str::from_utf8(&value).unwrap().parse().unwrap(),
+            frame => panic!("unexpected {:?}", frame),
+        }
+    })
+}
+
+// shared-state 服务端: 种子决定 GET 与 SET 之间有没有其它客户端插进来. 同一个种子的结果总是相同的, 不同的种子
+// 既有丢失更新的交错也有不丢失的交错. GET 与 SET 之间不等待时, 上千种交错都不会丢失更新
+#[test]
+fn simulated_shared_state_db() {
+    let outcomes: Vec<u64> = (0..200).map(|seed| simulated_increments(&simulation(seed), false)).collect();
+    for (seed, &outcome) in outcomes.iter().enumerate().take(20) {
+        assert_eq!(simulated_increments(&simulation(seed as u64), false), outcome, "seed {}", seed);
+    }
+    assert!(outcomes.iter().all(|&outcome| (1..=6).contains(&outcome)), "{:?}", outcomes);
+    assert!(outcomes.contains(&6), "no interleaving kept every update");
+    let distinct: HashSet<_> = outcomes.iter().collect();
+    assert!(distinct.len() > 2, "only {:?} outcomes", distinct);
+
+    sim::check(1000, |mini_tokio| assert_eq!(simulated_increments(mini_tokio, true), 6));
+}
+
+// channels-demo 中管理任务的模式: 客户端通过 mpsc 把命令发给独占连接的管理任务, 通过 oneshot 接收响应
+#[derive(Debug)]
+enum ManagerCommand {
+    Get { key: String, resp: oneshot::Sender<Option<Bytes>> },
+    Set { key: String, val: Bytes, resp: oneshot::Sender<()> },
+}
+
+// 在上千种交错下, 每个客户端 SET 之后的 GET 都能读到自己的值, 所有请求都得到响应, 管理任务在发送者都 drop 后退出
+#[test]
+fn simulated_channel_manager() {
+    sim::check(1000, |mini_tokio| {
+        mini_tokio.block_on(async {
+            let (tx, mut rx) = mpsc::channel(2);
+
+            let manager = spawn(async move {
+                // 代替 redis 连接的存储, 每个请求有一段模拟的往返延迟
+                let mut store = HashMap::new();
+                let mut handled = 0;
+                while let Some(message) = rx.recv().await {
+                    delay(Duration::from_millis(1)).await;
+                    match message {
+                        ManagerCommand::Get { key, resp } => {
+                            let _ = resp.send(store.get(&key).cloned());
+                        }
+                        ManagerCommand::Set { key, val, resp } => {
+                            store.insert(key, val);
+                            let _ = resp.send(());
+                        }
+                    }
+                    handled += 1;
+                }
+                handled
+            });
+
+            let clients: Vec<_> = (0..3)
+                .map(|client| {
+                    let mut tx = tx.clone();
+                    spawn(async move {
+                        let key = format!("key-{}", client);
+                        let val = Bytes::from(format!("value-{}", client));
+
+                        let (resp_tx, resp_rx) = oneshot::channel();
+                        let cmd = ManagerCommand::Set { key: key.clone(), val: val.clone(), resp: resp_tx };
+                        tx.send(cmd).await.unwrap();
+                        resp_rx.await.unwrap();
+
+                        let (resp_tx, resp_rx) = oneshot::channel();
+                        tx.send(ManagerCommand::Get { key, resp: resp_tx }).await.unwrap();
+                        assert_eq!(resp_rx.await.unwrap(), Some(val));
+                    })
+                })
+                .collect();
+            drop(tx);
+
+            for client in clients {
+                client.await.unwrap();
+            }
+            assert_eq!(manager.await.unwrap(), 6);
+        });
+    });
+}